@@ -0,0 +1,117 @@
+#[macro_use]
+extern crate bencher;
+extern crate ludomath;
+extern crate recurse_arena;
+
+use std::collections::HashMap;
+
+use bencher::Bencher;
+use ludomath::vec2d::*;
+use recurse_arena::*;
+
+// A walled square arena with a pillar every few tiles, so that entities
+// always have some walls around them.
+fn arena(size: usize) -> Arena {
+    let mut map = String::from("[legend]\nb = wall\nf = floor\n[map]\n");
+
+    for y in 0..size {
+        for x in 0..size {
+            let border = x == 0 || y == 0 || x == size - 1 || y == size - 1;
+            let pillar = x % 4 == 0 && y % 4 == 0;
+            map.push(if border || pillar { 'b' } else { 'f' });
+        }
+        map.push('\n');
+    }
+
+    map.parse().unwrap()
+}
+
+fn game_state(arena: &Arena, players: usize, bullets: usize) -> GameState {
+    let spawns = arena.spawn_points();
+    let mut state = GameState {
+        players: HashMap::new(),
+        bullets: vec![],
+        events: vec![],
+        tick: 0,
+    };
+
+    for i in 0..players {
+        let id = PlayerId(i as u32);
+        state
+            .players
+            .insert(id,
+                    Player {
+                        id,
+                        pos: spawns[i * 7919 % spawns.len()],
+                        dir: Vector::new(1.0, 0.0),
+                        health: Rules::default().player_health,
+                        ..Player::default()
+                    });
+    }
+
+    for i in 0..bullets {
+        let angle = i as f32;
+        state
+            .bullets
+            .push(Bullet {
+                      pos: spawns[i * 104729 % spawns.len()],
+                      vel: Vector::new(angle.cos(), angle.sin()) * 10.0,
+                      pid: PlayerId((i % players) as u32),
+                      id: BulletId(i as u32),
+                      rewind: 0,
+                  });
+    }
+
+    state
+}
+
+fn update(b: &mut Bencher, size: usize, bullets: usize) {
+    let arena = arena(size);
+    let state = game_state(&arena, 16, bullets);
+    let positions = PositionHistory::new(0);
+    let rules = Rules::default();
+
+    b.iter(|| {
+               let mut state = state.clone();
+               state.update(&rules, &arena, &positions);
+               state
+           });
+}
+
+fn map_16_bullets_100(b: &mut Bencher) {
+    update(b, 16, 100)
+}
+
+fn map_64_bullets_100(b: &mut Bencher) {
+    update(b, 64, 100)
+}
+
+fn map_256_bullets_100(b: &mut Bencher) {
+    update(b, 256, 100)
+}
+
+fn map_1024_bullets_100(b: &mut Bencher) {
+    update(b, 1024, 100)
+}
+
+fn map_64_bullets_500(b: &mut Bencher) {
+    update(b, 64, 500)
+}
+
+fn map_256_bullets_500(b: &mut Bencher) {
+    update(b, 256, 500)
+}
+
+fn map_1024_bullets_500(b: &mut Bencher) {
+    update(b, 1024, 500)
+}
+
+benchmark_group!(benches,
+                 map_16_bullets_100,
+                 map_64_bullets_100,
+                 map_256_bullets_100,
+                 map_1024_bullets_100,
+                 map_64_bullets_500,
+                 map_256_bullets_500,
+                 map_1024_bullets_500);
+benchmark_main!(benches);
@@ -0,0 +1,31 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate recurse_arena as ra;
+
+use ra::{Link, Message, ToServerMsg};
+
+// everything the server decodes from a client, whichever transport it's on
+fuzz_target!(|data: &[u8]| {
+    // as a TCP stream of frames
+    let mut stream = data;
+    while let Ok(msg) = ra::read_frame(&mut stream) {
+        check(msg);
+    }
+
+    // as a UDP datagram
+    let mut link = Link::new(ToServerMsg::max_len() as usize, 10.0);
+    if let Ok(msgs) = link.receive(data) {
+        for msg in msgs {
+            if let Ok(msg) = ra::decode(&msg) {
+                check(msg);
+            }
+        }
+    }
+});
+
+fn check(msg: ToServerMsg) {
+    if let ToServerMsg::Login(name, _) = msg {
+        let _ = ra::check_name(&name);
+    }
+}
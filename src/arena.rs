@@ -0,0 +1,340 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::error::Error;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use ludomath::vec2d::*;
+use serde::de::{self, Deserialize, Deserializer};
+
+use CSquare;
+
+static LOGO_MAP: &str = include_str!("../maps/logo.map");
+
+/// A rectangular grid of tiles, one world unit per tile.
+///
+/// Arenas are parsed from map files made of two sections: a `[legend]`
+/// mapping characters to tile kinds, and a `[map]` of equally long rows
+/// using those characters. Lines starting with `#` outside of the map are
+/// comments.
+///
+/// ```text
+/// [legend]
+/// b = wall
+/// f = floor
+///
+/// [map]
+/// bbbb
+/// bffb
+/// bbbb
+/// ```
+#[derive(Clone, Debug, Serialize)]
+pub struct Arena {
+    width: usize,
+    height: usize,
+    tiles: Vec<Tile>,
+}
+
+// an arena as it comes over the wire, checked before it's taken for one
+#[derive(Deserialize)]
+struct RawArena {
+    width: usize,
+    height: usize,
+    tiles: Vec<Tile>,
+}
+
+impl<'de> Deserialize<'de> for Arena {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let RawArena {
+            width,
+            height,
+            tiles,
+        } = RawArena::deserialize(deserializer)?;
+
+        if width.checked_mul(height) != Some(tiles.len()) {
+            let msg = format!("arena is {}x{} but has {} tiles", width, height, tiles.len());
+            return Err(de::Error::custom(msg));
+        }
+
+        // which also catches an arena with no tiles at all
+        if tiles.iter().all(|t| t.is_solid()) {
+            return Err(de::Error::custom("arena has no floor tiles"));
+        }
+
+        Ok(Arena {
+               width,
+               height,
+               tiles,
+           })
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Tile {
+    Wall,
+    Glow,
+    Void,
+    Edge,
+    Floor,
+}
+
+impl Tile {
+    pub fn is_solid(self) -> bool {
+        match self {
+            Tile::Wall | Tile::Glow | Tile::Void => true,
+            Tile::Edge | Tile::Floor => false,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Tile> {
+        match name {
+            "wall" => Some(Tile::Wall),
+            "glow" => Some(Tile::Glow),
+            "void" => Some(Tile::Void),
+            "edge" => Some(Tile::Edge),
+            "floor" => Some(Tile::Floor),
+            _ => None,
+        }
+    }
+}
+
+impl Arena {
+    /// The arena the game shipped with.
+    pub fn logo() -> Self {
+        LOGO_MAP.parse().expect("built-in map is invalid")
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ArenaError> {
+        let mut s = String::new();
+        File::open(path)?.read_to_string(&mut s)?;
+        s.parse()
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn tile(&self, x: usize, y: usize) -> Tile {
+        assert!(x < self.width);
+        assert!(y < self.height);
+        self.tiles[y * self.width + x]
+    }
+
+    pub fn bounds(&self) -> CSquare {
+        CSquare {
+            top_left: Vector::default(),
+            bottom_right: Vector::new(self.width as f32, self.height as f32),
+        }
+    }
+
+    /// Solid tiles overlapping `bounds`. Only the tiles under `bounds` are
+    /// visited, so the cost doesn't depend on the size of the arena.
+    pub fn walls_near(&self, bounds: CSquare) -> Walls {
+        let clamp = |v: f32, max: usize| (v.max(0.0) as usize).min(max);
+
+        let x_start = clamp(bounds.top_left.x.floor(), self.width);
+        let x_end = clamp(bounds.bottom_right.x.ceil(), self.width);
+        let y_start = clamp(bounds.top_left.y.floor(), self.height);
+        let y_end = clamp(bounds.bottom_right.y.ceil(), self.height);
+
+        Walls {
+            arena: self,
+            x_start,
+            x_end,
+            y_end,
+            x: x_start,
+            y: if x_start < x_end { y_start } else { y_end },
+        }
+    }
+
+    /// Centers of all tiles a player can stand on.
+    pub fn spawn_points(&self) -> Vec<Vector> {
+        let mut points = vec![];
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if !self.tile(x, y).is_solid() {
+                    points.push(Vector::new(x as f32 + 0.5, y as f32 + 0.5));
+                }
+            }
+        }
+
+        points
+    }
+}
+
+pub struct Walls<'a> {
+    arena: &'a Arena,
+    x_start: usize,
+    x_end: usize,
+    y_end: usize,
+    x: usize,
+    y: usize,
+}
+
+impl<'a> Iterator for Walls<'a> {
+    type Item = CSquare;
+
+    fn next(&mut self) -> Option<CSquare> {
+        while self.y < self.y_end {
+            let (x, y) = (self.x, self.y);
+
+            self.x += 1;
+            if self.x == self.x_end {
+                self.x = self.x_start;
+                self.y += 1;
+            }
+
+            if self.arena.tile(x, y).is_solid() {
+                return Some(CSquare::new(Vector::new(x as f32, y as f32), 1.0));
+            }
+        }
+
+        None
+    }
+}
+
+impl FromStr for Arena {
+    type Err = ArenaError;
+
+    fn from_str(s: &str) -> Result<Self, ArenaError> {
+        enum Section {
+            None,
+            Legend,
+            Map,
+        }
+
+        let mut section = Section::None;
+        let mut legend = HashMap::new();
+        let mut tiles = vec![];
+        let mut width = None;
+        let mut height = 0;
+
+        for (i, line) in s.lines().enumerate() {
+            let line_no = i + 1;
+            let err = |msg: String| ArenaError::Parse(line_no, msg);
+
+            match line.trim() {
+                "[legend]" => {
+                    section = Section::Legend;
+                    continue;
+                }
+                "[map]" => {
+                    if legend.is_empty() {
+                        return Err(err("[map] must come after a non-empty [legend]".into()));
+                    }
+                    section = Section::Map;
+                    continue;
+                }
+                _ => {}
+            }
+
+            match section {
+                Section::Map => {
+                    let row = line.trim_right();
+                    if row.is_empty() {
+                        continue;
+                    }
+
+                    let mut row_width = 0;
+                    for c in row.chars() {
+                        match legend.get(&c) {
+                            Some(&tile) => tiles.push(tile),
+                            None => return Err(err(format!("'{}' is not in the legend", c))),
+                        }
+                        row_width += 1;
+                    }
+
+                    match width {
+                        None => width = Some(row_width),
+                        Some(w) if w != row_width => {
+                            return Err(err(format!("row is {} tiles wide, expected {}",
+                                                   row_width,
+                                                   w)));
+                        }
+                        Some(_) => {}
+                    }
+
+                    height += 1;
+                }
+                _ if line.trim().is_empty() || line.trim().starts_with('#') => {}
+                Section::Legend => {
+                    let mut parts = line.splitn(2, '=');
+                    let key = parts.next().unwrap().trim();
+                    let name = match parts.next() {
+                        Some(name) => name.trim(),
+                        None => return Err(err("expected `<char> = <tile>`".into())),
+                    };
+
+                    let mut chars = key.chars();
+                    let c = match (chars.next(), chars.next()) {
+                        (Some(c), None) => c,
+                        _ => return Err(err(format!("'{}' is not a single character", key))),
+                    };
+
+                    let tile = match Tile::from_name(name) {
+                        Some(tile) => tile,
+                        None => return Err(err(format!("unknown tile kind '{}'", name))),
+                    };
+
+                    legend.insert(c, tile);
+                }
+                Section::None => {
+                    return Err(err("expected [legend] section".into()));
+                }
+            }
+        }
+
+        let width = match width {
+            Some(w) => w,
+            None => return Err(ArenaError::Parse(s.lines().count(), "map is empty".into())),
+        };
+
+        if tiles.iter().all(|t| t.is_solid()) {
+            return Err(ArenaError::Parse(s.lines().count(), "map has no floor tiles".into()));
+        }
+
+        Ok(Arena {
+               width,
+               height,
+               tiles,
+           })
+    }
+}
+
+#[derive(Debug)]
+pub enum ArenaError {
+    Io(io::Error),
+    // line number and description
+    Parse(usize, String),
+}
+
+impl From<io::Error> for ArenaError {
+    fn from(e: io::Error) -> Self {
+        ArenaError::Io(e)
+    }
+}
+
+impl fmt::Display for ArenaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ArenaError::Io(ref e) => write!(f, "could not read map: {}", e),
+            ArenaError::Parse(line, ref msg) => write!(f, "line {}: {}", line, msg),
+        }
+    }
+}
+
+impl Error for ArenaError {
+    fn description(&self) -> &str {
+        match *self {
+            ArenaError::Io(_) => "could not read map",
+            ArenaError::Parse(..) => "invalid map",
+        }
+    }
+}
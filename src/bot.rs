@@ -0,0 +1,494 @@
+// Headless players for finding out how many a server can hold. Each one
+// connects the way the game client does, walks, turns and shoots at random,
+// and every second the tick rate, traffic and update latency they're seeing
+// are reported, while more of them keep joining.
+//
+//     cargo run --release --bin recurse_arena_server -- 8000
+//     cargo run --release --bin recurse_arena_bot -- 127.0.0.1:8000 --clients 300
+
+extern crate bincode as bc;
+extern crate ludomath;
+extern crate recurse_arena as ra;
+extern crate structopt;
+#[macro_use]
+extern crate structopt_derive;
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ludomath::rng::Rng;
+use ludomath::vec2d::*;
+use ra::{Button, Conn, FromServerMsg, Input, IntoSecs, PlayerField, PlayerId, Rules,
+         ToServerMsg, Transport, UdpPeer};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(name = "Recurse Arena load testing bots")]
+struct Opt {
+    #[structopt(help = "Server to connect to", default_value = "127.0.0.1:8000")]
+    server: String,
+    #[structopt(long = "clients", help = "How many players to simulate", default_value = "200")]
+    clients: usize,
+    #[structopt(long = "seconds", help = "How long to play for once everyone has joined",
+                default_value = "30")]
+    seconds: u64,
+    #[structopt(long = "ramp-up", help = "Milliseconds between players joining",
+                default_value = "50")]
+    ramp_up: u64,
+    #[structopt(long = "inputs", help = "Inputs each player sends a second",
+                default_value = "4")]
+    inputs: f32,
+    #[structopt(long = "transport", help = "Protocol to connect over, udp or tcp",
+                default_value = "udp")]
+    transport: Transport,
+}
+
+// what the bots have seen since the last report
+#[derive(Default)]
+struct Window {
+    updates: u64,
+    // of messages as they're encoded, not counting framing or packet
+    // headers
+    bytes: u64,
+    oldest_tick: Option<u64>,
+    newest_tick: u64,
+    // seconds from sending an input to getting the update that has it
+    latencies: Vec<f32>,
+}
+
+struct Report {
+    joined: bool,
+    // the server's, from its rules
+    ticks_per_second: u32,
+    updates: u64,
+    secs: f32,
+    // why they stopped early, if they did
+    error: Option<String>,
+}
+
+fn main() {
+    let Opt {
+        server,
+        clients,
+        seconds,
+        ramp_up,
+        inputs,
+        transport,
+    } = Opt::from_args();
+
+    println!("Connecting {} clients to {} over {:?}...",
+             clients,
+             server,
+             transport);
+
+    let start = Instant::now();
+    let ramp_up = Duration::from_millis(ramp_up);
+    let end = start + ramp_up * clients as u32 + Duration::from_secs(seconds);
+
+    let window = Arc::new(Mutex::new(Window::default()));
+    let mut all_latencies = vec![];
+    // when, how many clients there were then, and the newest tick seen
+    let mut last_report = (start, 0, None);
+
+    let mut threads = vec![];
+    let mut next_join = start;
+
+    while Instant::now() < end {
+        let now = Instant::now();
+
+        if threads.len() < clients && now >= next_join {
+            let server = server.clone();
+            let window = window.clone();
+            let i = threads.len();
+            threads.push(thread::spawn(move || bot(i, &server, transport, inputs, end, &window)));
+            next_join += ramp_up;
+            continue;
+        }
+
+        if now.duration_since(last_report.0) >= Duration::from_secs(1) {
+            let seen = mem::replace(&mut *window.lock().unwrap(), Window::default());
+            let (then, clients_then, newest_then) = last_report;
+            let secs = now.duration_since(then).into_secs();
+
+            // more may have joined since the last report
+            let average_clients = (clients_then + threads.len()) as f32 / 2.0;
+
+            let (ticks, newest) = match seen.oldest_tick {
+                Some(oldest) => {
+                    let ticks = seen.newest_tick
+                        .saturating_sub(newest_then.unwrap_or(oldest));
+                    (ticks, Some(seen.newest_tick))
+                }
+                None => (0, newest_then),
+            };
+
+            report(start.elapsed().into_secs(),
+                   threads.len(),
+                   average_clients,
+                   &seen,
+                   ticks,
+                   secs);
+
+            all_latencies.extend(seen.latencies);
+            last_report = (now, threads.len(), newest);
+            continue;
+        }
+
+        let mut wake = last_report.0 + Duration::from_secs(1);
+        if threads.len() < clients && next_join < wake {
+            wake = next_join;
+        }
+        if wake > now {
+            thread::sleep(wake - now);
+        }
+    }
+
+    let reports: Vec<Report> = threads
+        .into_iter()
+        .map(|t| {
+                 t.join()
+                     .unwrap_or_else(|_| {
+                                         Report {
+                                             joined: false,
+                                             ticks_per_second: 0,
+                                             updates: 0,
+                                             secs: 0.0,
+                                             error: Some("bot panicked".to_string()),
+                                         }
+                                     })
+             })
+        .collect();
+
+    let joined = reports.iter().filter(|r| r.joined).count();
+    println!("{} of {} clients joined", joined, clients);
+
+    let mut errors = HashMap::new();
+    for error in reports.iter().filter_map(|r| r.error.as_ref()) {
+        *errors.entry(error.clone()).or_insert(0) += 1;
+    }
+    for (error, count) in errors {
+        println!("{} clients stopped early: {}", count, error);
+    }
+
+    let mut rates: Vec<f32> = reports
+        .iter()
+        .filter(|r| r.joined && r.secs > 0.0)
+        .map(|r| r.updates as f32 / r.secs)
+        .collect();
+
+    if rates.is_empty() {
+        return;
+    }
+
+    let ticks_per_second = reports
+        .iter()
+        .map(|r| r.ticks_per_second)
+        .max()
+        .unwrap_or(0);
+
+    rates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mean = rates.iter().sum::<f32>() / rates.len() as f32;
+    println!("Updates per second per client: min {:.1}, median {:.1}, mean {:.1}, max {:.1} \
+              (the server ticks {} times a second)",
+             rates[0],
+             rates[rates.len() / 2],
+             mean,
+             rates[rates.len() - 1],
+             ticks_per_second);
+
+    all_latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    println!("Update latency overall: {}", percentiles(&all_latencies));
+}
+
+fn report(at: f32, clients: usize, average_clients: f32, seen: &Window, ticks: u64, secs: f32) {
+    let mut latencies = seen.latencies.clone();
+    latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let per_client = if average_clients > 0.0 {
+        seen.updates as f32 / average_clients / secs
+    } else {
+        0.0
+    };
+
+    println!("[{:5.1}s] {} clients: {:.1} ticks/s, {:.1} updates/s per client, {:.1} KB/s of \
+              messages in, latency {}",
+             at,
+             clients,
+             ticks as f32 / secs,
+             per_client,
+             seen.bytes as f32 / 1024.0 / secs,
+             percentiles(&latencies));
+}
+
+// of sorted latencies, in milliseconds
+fn percentiles(sorted: &[f32]) -> String {
+    if sorted.is_empty() {
+        return "n/a".to_string();
+    }
+
+    let p = |q: f32| {
+        let i = (q * sorted.len() as f32) as usize;
+        sorted[i.min(sorted.len() - 1)] * 1000.0
+    };
+
+    format!("p50 {:.0}ms, p90 {:.0}ms, p99 {:.0}ms, max {:.0}ms",
+            p(0.5),
+            p(0.9),
+            p(0.99),
+            sorted[sorted.len() - 1] * 1000.0)
+}
+
+fn bot(i: usize,
+       server: &str,
+       transport: Transport,
+       inputs: f32,
+       end: Instant,
+       window: &Mutex<Window>)
+       -> Report {
+    let mut report = Report {
+        joined: false,
+        ticks_per_second: 0,
+        updates: 0,
+        secs: 0.0,
+        error: None,
+    };
+
+    let (mut conn, id, rules) = match join(server, transport, &format!("bot{}", i)) {
+        Ok(joined) => joined,
+        Err(e) => {
+            report.error = Some(format!("couldn't join: {}", e));
+            return report;
+        }
+    };
+
+    report.joined = true;
+    report.ticks_per_second = rules.ticks_per_second;
+    let start = Instant::now();
+
+    if let Err(e) = play(&mut conn, id, &rules, inputs, end, window, &mut report) {
+        report.error = Some(e);
+    }
+
+    report.secs = start.elapsed().into_secs();
+    let _ = conn.send(&ToServerMsg::Logout, true);
+    report
+}
+
+fn join(server: &str, transport: Transport, name: &str) -> io::Result<(Conn, PlayerId, Rules)> {
+    let mut conn = match transport {
+        Transport::Tcp => {
+            let mut stream = TcpStream::connect(server)?;
+            // so a server that stops answering can't leave the bot hanging
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+            ra::handshake(&mut stream)?;
+            Conn::Tcp(stream)
+        }
+        Transport::Udp => {
+            let peer = UdpPeer::connect(server)?;
+            peer.set_read_timeout(Some(Duration::from_millis(100)))?;
+            Conn::Udp(peer)
+        }
+    };
+
+    conn.send(&ToServerMsg::Login(name.to_string(), None), true)
+        .map_err(to_io_error)?;
+
+    // the server only learns about a UDP client from its packets, so keep
+    // knocking until it answers, and acking the pieces of a big welcome as
+    // they come in
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        let received = conn.recv();
+        if let Conn::Udp(ref mut peer) = conn {
+            peer.flush()?;
+        }
+
+        let msgs = match received {
+            Ok(msgs) => msgs,
+            Err(ref e) if is_timeout(e) => {
+                if let Conn::Udp(_) = conn {
+                    continue;
+                }
+                break;
+            }
+            Err(e) => return Err(to_io_error(e)),
+        };
+
+        for msg in msgs {
+            match msg {
+                FromServerMsg::Welcome(id, _, _, rules) => {
+                    if let Conn::Udp(ref peer) = conn {
+                        peer.set_read_timeout(Some(Duration::from_secs(1)))?;
+                    }
+                    return Ok((conn, id, rules));
+                }
+                FromServerMsg::Disconnect { reason } => {
+                    return Err(io::Error::new(io::ErrorKind::Other, reason))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::TimedOut, "server didn't answer"))
+}
+
+// acks every update and answers pings like a real client, and now and then
+// changes what it's doing
+fn play(conn: &mut Conn,
+        id: PlayerId,
+        rules: &Rules,
+        inputs: f32,
+        end: Instant,
+        window: &Mutex<Window>,
+        report: &mut Report)
+        -> Result<(), String> {
+    let mut rng = Rng::new();
+    let mut controls = Controls::new(&mut rng);
+    // inputs waiting to show up in an update, and when they were sent
+    let mut sent: Vec<(u32, Instant)> = vec![];
+    let mut seq = 0;
+
+    while Instant::now() < end {
+        let msgs = match conn.recv::<FromServerMsg>() {
+            Ok(msgs) => msgs,
+            // a TCP read that timed out may have stopped part way through a
+            // message, so there's no going on from it
+            Err(ref e) if is_timeout(e) => {
+                match *conn {
+                    Conn::Udp(_) if !conn.timed_out() => continue,
+                    _ => return Err("server stopped sending".to_string()),
+                }
+            }
+            Err(e) => return Err(e.to_string()),
+        };
+
+        for msg in msgs {
+            let bytes = bc::serialized_size(&msg);
+
+            match msg {
+                // the state itself isn't needed, acking is what makes the
+                // server do the work of sending deltas
+                FromServerMsg::Update(delta) => {
+                    report.updates += 1;
+                    conn.send(&ToServerMsg::Ack(delta.tick), false)
+                        .map_err(|e| e.to_string())?;
+
+                    let handled = delta
+                        .players
+                        .iter()
+                        .filter(|&&(pid, _)| pid == id)
+                        .flat_map(|&(_, ref fields)| fields)
+                        .filter_map(|f| match *f {
+                                        PlayerField::LastInput(seq, _) => Some(seq),
+                                        _ => None,
+                                    })
+                        .next();
+
+                    let mut window = window.lock().unwrap();
+                    window.updates += 1;
+                    window.bytes += bytes;
+                    window.newest_tick = window.newest_tick.max(delta.tick);
+                    window.oldest_tick = Some(window
+                                                  .oldest_tick
+                                                  .map_or(delta.tick, |t| t.min(delta.tick)));
+
+                    if let Some(handled) = handled {
+                        for &(_, at) in sent.iter().filter(|&&(s, _)| s <= handled) {
+                            window.latencies.push(at.elapsed().into_secs());
+                        }
+                        sent.retain(|&(s, _)| s > handled);
+                    }
+                    drop(window);
+
+                    if rng.rand_float(0.0, rules.ticks_per_second as f32) < inputs {
+                        let input = controls.next(&mut rng, delta.tick);
+                        seq += 1;
+                        conn.send(&ToServerMsg::Input(seq, input), true)
+                            .map_err(|e| e.to_string())?;
+                        sent.push((seq, Instant::now()));
+                    }
+                }
+                FromServerMsg::Ping(n) => {
+                    window.lock().unwrap().bytes += bytes;
+                    conn.send(&ToServerMsg::Pong(n), false)
+                        .map_err(|e| e.to_string())?
+                }
+                FromServerMsg::Disconnect { reason } => return Err(reason),
+                _ => window.lock().unwrap().bytes += bytes,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// roughly what a person does: holds down a direction or two for a while,
+// sweeps the mouse around and clicks
+struct Controls {
+    held: Vec<Button>,
+    angle: f32,
+    firing: bool,
+}
+
+impl Controls {
+    fn new(rng: &mut Rng) -> Self {
+        Controls {
+            held: vec![],
+            angle: rng.rand_float(0.0, 360.0),
+            firing: false,
+        }
+    }
+
+    fn next(&mut self, rng: &mut Rng, tick: u64) -> Input {
+        if self.firing {
+            self.firing = false;
+            return Input::Release(Button::LeftMouse);
+        }
+
+        match rng.rand_uint(0, 3) {
+            0 => {
+                let keys = [Button::W, Button::A, Button::S, Button::D];
+                let b = keys[rng.rand_uint(0, keys.len() as u64) as usize];
+
+                if let Some(i) = self.held.iter().position(|&h| h == b) {
+                    self.held.remove(i);
+                    Input::Release(b)
+                } else {
+                    self.held.push(b);
+                    Input::Press(b, self.dir(), tick)
+                }
+            }
+            1 => {
+                self.angle += rng.rand_float(-45.0, 45.0);
+                Input::DirChanged(self.dir())
+            }
+            _ => {
+                self.firing = true;
+                Input::Press(Button::LeftMouse, self.dir(), tick)
+            }
+        }
+    }
+
+    fn dir(&self) -> Vector {
+        Vector::new(self.angle.to_radians().cos(), self.angle.to_radians().sin())
+    }
+}
+
+fn is_timeout(e: &bc::Error) -> bool {
+    match **e {
+        bc::ErrorKind::IoError(ref e) => {
+            e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+        }
+        _ => false,
+    }
+}
+
+fn to_io_error(e: bc::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
@@ -1,4 +1,6 @@
 
+#[macro_use]
+extern crate log;
 extern crate recurse_arena as ra;
 extern crate piston;
 extern crate graphics;
@@ -15,7 +17,8 @@ extern crate structopt;
 #[macro_use]
 extern crate structopt_derive;
 
-use ra::{GameState, LOGO, LOGO_WIDTH, LOGO_HEIGHT, PLAYER_HEALTH, PLAYER_RADIUS, IntoSecs};
+use ra::{GameState, Arena, Rules, Tile, Conn, Transport, UdpPeer, PLAYER_RADIUS, IntoSecs,
+         LogFilter, LogFormat};
 
 use std::io;
 use std::time::{Instant, Duration};
@@ -24,7 +27,7 @@ use std::process;
 use std::io::prelude::*;
 use std::net::TcpStream;
 use std::collections::{HashMap, VecDeque};
-use std::sync::mpsc::{channel, Receiver};
+use std::sync::mpsc::{channel, Sender, Receiver};
 
 use piston::window::*;
 use piston::event_loop::*;
@@ -69,6 +72,12 @@ static HIT: &[u8] = include_bytes!("../assets/Hitmarker.ogg");
 
 static MUSIC: &[u8] = include_bytes!("../assets/Cut and Run.ogg");
 
+// predicted steps the server hasn't confirmed yet are kept for replaying,
+// up to this many seconds' worth
+const MAX_PREDICTED_SECS: u32 = 2;
+// one a second, the server keeps a dropped player around for 30 by default
+const RECONNECT_ATTEMPTS: u32 = 30;
+
 
 #[derive(StructOpt)]
 #[structopt(name = "Recurse Arena")]
@@ -77,6 +86,21 @@ struct Opt {
     username: String,
     #[structopt(help = "IP address of the server to connect to")]
     server_ip: String,
+    #[structopt(long = "transport", help = "Protocol to talk to the server with, udp or tcp",
+                default_value = "udp")]
+    transport: Transport,
+    #[structopt(long = "interpolation-delay",
+                help = "How far in the past other players are drawn, in milliseconds",
+                default_value = "100")]
+    interpolation_delay: u32,
+    #[structopt(long = "log",
+                help = "What to log, a level or module=level pairs like \
+                        info,recurse_arena=debug",
+                default_value = "info")]
+    log: LogFilter,
+    #[structopt(long = "log-format", help = "text, or json for one object a line",
+                default_value = "text")]
+    log_format: LogFormat,
 }
 
 
@@ -84,9 +108,32 @@ fn main() {
     let Opt {
         username,
         server_ip,
+        transport,
+        interpolation_delay,
+        log,
+        log_format,
     } = Opt::from_args();
 
-    let mut stream = connect(server_ip);
+    ra::init_logging(log, log_format);
+
+    if let Err(e) = ra::check_name(&username) {
+        error!("Can't play with that name: {}", e);
+        process::exit(-1);
+    }
+
+    let (mut conn, player_id, session, arena, rules) =
+        match join(&server_ip, transport, &username, None) {
+            Ok(joined) => joined,
+            Err(e) => {
+                error!("Failed to connect: {}", e);
+                process::exit(-1);
+            }
+        };
+
+    info!(player = player_id; "Joined the game");
+    info!("Playing on a {}x{} arena", arena.width(), arena.height());
+    let start_pos = arena.spawn_points()[0];
+
     let opengl = OpenGL::V3_2;
     let (full_width, full_height) = glutin::get_primary_monitor().get_dimensions();
 
@@ -147,50 +194,23 @@ fn main() {
         hitmarker,
     };
 
-    println!("Attempting to receive player_id from server...");
-    let player_id = match bc::deserialize_from(&mut stream, bc::Infinite).unwrap() {
-        ra::FromServerMsg::Welcome(id) => id,
-        _ => unreachable!(),
-    };
-
-    println!("Got player_id from server: {}", player_id.0);
-
-    let msg = ra::ToServerMsg::Login(player_id, username.clone());
-    bc::serialize_into(&mut stream, &msg, bc::Infinite).unwrap();
-
-    println!("Sent login request");
+    debug!("Spawning listener thread...");
+    let conn_clone = conn.try_clone().unwrap();
+    let listener_sender = sender.clone();
+    thread::spawn(move || listen(conn_clone, listener_sender));
 
-    println!("Spawning listener thread...");
-    let mut stream_clone = stream.try_clone().unwrap();
-
-    thread::spawn(move || {
-        loop {
-            // try to read new state from server
-            match bc::deserialize_from(&mut stream_clone, bc::Infinite) {
-                Ok(msg) => {
-                    match msg {
-                        ra::FromServerMsg::Update(gs) => {
-                            if sender.send(gs).is_err() {
-                                break;
-                            }
-                        }
-                        _ => panic!("Protocol error / unimplemented"),
-                    }
-                }
-                Err(e) => {
-                    println!("Listener thread: Error: {}", e);
-                    break;
-                }
-            }
-        }
-    });
+    let interpolation = ra::Interpolation::new(interpolation_delay as f32 / 1000.0,
+                                               rules.ticks_per_second);
 
     let mut state = State {
         game_state: GameState {
             players: HashMap::new(),
             bullets: vec![],
             events: vec![],
+            tick: 0,
         },
+        arena,
+        rules,
         player_id,
         window_size: (0, 0),
         mouse_screen: Vector::default(),
@@ -202,18 +222,38 @@ fn main() {
         begin_time: Instant::now(),
         flash: Instant::now() - Duration::from_secs(10),
         messages: VecDeque::new(),
+        server_ip,
+        transport,
+        username: username.clone(),
+        session,
+        sender,
+        reconnecting: false,
+        input_seq: 0,
+        predicted: VecDeque::new(),
+        accumulator: 0.0,
+        interpolation,
+        names: HashMap::new(),
+        view: GameState {
+            players: HashMap::new(),
+            bullets: vec![],
+            events: vec![],
+            tick: 0,
+        },
     };
 
     let player = ra::Player {
-        health: PLAYER_HEALTH,
+        health: state.rules.player_health,
         id: player_id,
         name: username,
         dir: VEC_RIGHT,
-        pos: Vector::new(1.5, 1.5),
+        pos: start_pos,
         vel: VEC_ZERO,
         force: VEC_ZERO,
         respawn_timer: 0.0,
         score: 0,
+        last_input: 0,
+        last_input_tick: 0,
+        rtt: 0.0,
     };
 
     state.game_state.players.insert(player_id, player);
@@ -227,9 +267,14 @@ fn main() {
                      &mut gl,
                      &mut assets,
                      &mut window,
-                     &mut stream,
+                     &mut conn,
                      &reciever);
     }
+
+    // nothing to do if this fails, the server will notice eventually
+    if let Stage::Playing(_) = stage {
+        let _ = conn.send(&ra::ToServerMsg::Logout, true);
+    }
 }
 
 struct Assets<'a> {
@@ -250,8 +295,8 @@ fn step(e: Input,
         gl: &mut GlGraphics,
         assets: &mut Assets,
         window: &mut GlutinWindow,
-        mut stream: &mut TcpStream,
-        reciever: &Receiver<GameState>)
+        conn: &mut Conn,
+        reciever: &Receiver<Received>)
         -> Stage {
     match stage {
         Stage::Playing(mut state) => {
@@ -287,15 +332,93 @@ fn step(e: Input,
                     state.window_size = (width, height);
 
                     let mut events = vec![];
+                    let mut received = None;
+                    let mut disconnected = None;
+
+                    for r in reciever.try_iter() {
+                        match r {
+                            Received::State(gs) => {
+                                received = Some(gs.tick);
+                                state.interpolation.push(gs.clone());
+                                state.game_state = gs;
+                            }
+                            Received::Events(e) => events.extend(e),
+                            Received::Lost(reason) => {
+                                if !state.reconnecting {
+                                    warn!(tick = state.game_state.tick; "{}, reconnecting", reason);
+                                    state.reconnecting = true;
+                                    state
+                                        .messages
+                                        .push_front(("Connection lost, reconnecting...".into(),
+                                                     Instant::now()));
+
+                                    let ip = state.server_ip.clone();
+                                    let transport = state.transport;
+                                    let name = state.username.clone();
+                                    let session = state.session;
+                                    let sender = state.sender.clone();
+                                    thread::spawn(move || {
+                                                      reconnect(ip,
+                                                                transport,
+                                                                name,
+                                                                session,
+                                                                sender)
+                                                  });
+                                }
+                            }
+                            Received::Reconnected(new_conn, id, session, arena, rules) => {
+                                *conn = new_conn;
+                                state.reconnecting = false;
+                                state.player_id = id;
+                                state.session = session;
+                                state.arena = arena;
+                                state.interpolation.reset(rules.ticks_per_second);
+                                state.rules = rules;
+                                // whatever was held down got released on the
+                                // old connection
+                                state.buttons_down.clear();
+                                state.predicted.clear();
+                                state
+                                    .messages
+                                    .push_front(("Reconnected".into(), Instant::now()));
+                            }
+                            Received::Disconnected(reason) => disconnected = Some(reason),
+                        }
+                    }
 
-                    for gs in reciever.try_iter() {
-                        state.game_state = gs;
-                        events.extend(state.game_state.events.drain(..));
+                    if let Some(reason) = disconnected {
+                        return Stage::Disconnected(reason);
                     }
 
+                    for p in state.game_state.players.values() {
+                        state
+                            .names
+                            .entry(p.id)
+                            .or_insert_with(|| p.name.clone());
+                    }
+
+                    if let Some(tick) = received {
+                        state.send_input(conn, &ra::ToServerMsg::Ack(tick));
+                        state.reconcile();
+                    }
+
+                    // move our own player right away instead of waiting to hear
+                    // back from the server
+                    let tick = state.rules.dt();
+                    state.accumulator = (state.accumulator + dt).min(tick * 10.0);
+                    while state.accumulator >= tick {
+                        state.accumulator -= tick;
+                        state.predict(tick);
+                    }
+
+                    state.interpolation.advance(dt);
+                    state.update_view();
+
                     for event in events {
                         match event {
-                            ra::Event::BulletHitWall(ra::Bullet { pos, vel, .. }) => {
+                            ra::Event::BulletHitWall(ra::Bullet { vel, .. }, hit) => {
+                                let pos = hit.point;
+
                                 // spawn sparks
                                 let n = state.rng.rand_int(5, 10);
                                 for _ in 0..n {
@@ -316,7 +439,7 @@ fn step(e: Input,
                                 state.play_sound_at(&mut assets.splat, pos);
                             }
 
-                            ra::Event::BulletHitPlayer(b, pid, _damage_fraction) => {
+                            ra::Event::BulletHitPlayer(b, pid, _damage_fraction, _hit) => {
                                 if b.pid == state.player_id {
                                     assets.hitmarker.play();
                                 }
@@ -335,9 +458,8 @@ fn step(e: Input,
                                     assets.death.play();
                                 }
 
-
-                                let killed = state.game_state.players[&killed].name.clone();
-                                let killer = state.game_state.players[&killer].name.clone();
+                                let killed = state.name(killed);
+                                let killer = state.name(killer);
                                 let msgs = [format!("{} was killed by {}", killed, killer),
                                             format!("{} got wrecked by {}", killed, killer),
                                             format!("{} was annihilated by {}", killed, killer),
@@ -349,11 +471,14 @@ fn step(e: Input,
                             }
 
                             ra::Event::PlayerRespawned(id) => {
-                                if state.player_id == id {
-                                    let t = state.game_state.players[&id].respawn_timer;
-                                    state.begin_time = Instant::now() -
-                                                       Duration::from_millis((t * 1000.0) as u64);
-                                }
+                                let me = match state.game_state.players.get(&id) {
+                                    Some(p) if id == state.player_id => p,
+                                    _ => continue,
+                                };
+
+                                let t = me.respawn_timer;
+                                state.begin_time = Instant::now() -
+                                                   Duration::from_millis((t * 1000.0) as u64);
                             }
 
                             ra::Event::BulletFired(pos) => {
@@ -366,18 +491,18 @@ fn step(e: Input,
                                     continue;
                                 }
 
-                                let name = &state.game_state.players[&id].name;
-                                state
-                                    .messages
-                                    .push_front((format!("{} has joined the game", name),
-                                                 Instant::now()));
+                                let msg = format!("{} has joined the game", state.name(id));
+                                state.messages.push_front((msg, Instant::now()));
                             }
 
-                            ra::Event::PlayerLeft(name) => {
-                                state
-                                    .messages
-                                    .push_front((format!("{} left the game", name),
-                                                 Instant::now()));
+                            ra::Event::PlayerLeft(id, reason) => {
+                                let name = state.name(id);
+                                let msg = match reason {
+                                    ra::LeaveReason::Quit => format!("{} left the game", name),
+                                    ra::LeaveReason::TimedOut => format!("{} timed out", name),
+                                    ra::LeaveReason::Kicked => format!("{} was kicked", name),
+                                };
+                                state.messages.push_front((msg, Instant::now()));
                             }
                         }
                     }
@@ -393,19 +518,20 @@ fn step(e: Input,
                         state.buttons_down.insert(button, Instant::now());
 
                         if let Some(button) = convert_button(button) {
-                            let msg =
-                                ra::ToServerMsg::Input(state.player_id,
-                                                       ra::Input::Press(button,
-                                                                        state.player_dir()));
-                            send_input(stream, &msg);
+                            let seen = state
+                                .interpolation
+                                .tick()
+                                .unwrap_or(state.game_state.tick);
+                            let input = ra::Input::Press(button, state.player_dir(), seen);
+                            let msg = state.input_msg(input);
+                            state.send_input(conn, &msg);
                         }
                     }
                 }
                 Input::Release(button) => {
                     if let Some(button) = convert_button(button) {
-                        let msg = ra::ToServerMsg::Input(state.player_id,
-                                                         ra::Input::Release(button));
-                        send_input(stream, &msg);
+                        let msg = state.input_msg(ra::Input::Release(button));
+                        state.send_input(conn, &msg);
                     }
                     state.buttons_down.remove(&button);
                 }
@@ -421,20 +547,29 @@ fn step(e: Input,
                     state.player_dir = (mouse - player_pos).normalize();
 
                     let input = ra::Input::DirChanged(state.player_dir);
-                    let msg = ra::ToServerMsg::Input(state.player_id, input);
-                    send_input(stream, &msg);
+                    let msg = state.input_msg(input);
+                    state.send_input(conn, &msg);
                 }
                 _ => {}
             }
 
             Stage::Playing(state)
         }
-    }
-}
+        Stage::Disconnected(reason) => {
+            if let Input::Render(a) = e {
+                gl.draw(a.viewport(), |c, g| {
+                    clear(WHITE, g);
+
+                    let size = 30;
+                    let w = assets.cache.width(size, &reason);
+                    let t = c.transform
+                        .trans(a.width as f64 / 2.0 - w / 2.0, a.height as f64 / 2.0);
+                    text(BLACK, size, &reason, &mut assets.cache, t, g);
+                });
+            }
 
-fn send_input(stream: &mut TcpStream, msg: &ra::ToServerMsg) {
-    if let Err(e) = bc::serialize_into(stream, &msg, bc::Infinite) {
-        panic!("Error while sending input: {}", e);
+            Stage::Disconnected(reason)
+        }
     }
 }
 
@@ -460,22 +595,226 @@ fn convert_button(b: Button) -> Option<ra::Button> {
 
 enum Stage {
     Playing(State),
+    // the server went away, with the reason why
+    Disconnected(String),
+}
+
+// what the listener thread hands to the game loop
+enum Received {
+    State(GameState),
+    Events(Vec<ra::Event>),
+    // the connection dropped, worth trying to get back in
+    Lost(String),
+    Reconnected(Conn, ra::PlayerId, ra::SessionToken, Arena, Rules),
+    // the server doesn't want us back
+    Disconnected(String),
+}
+
+// connects and logs in, picking up where `session` left off if there is one
+fn join(ip: &str,
+        transport: Transport,
+        name: &str,
+        session: Option<ra::SessionToken>)
+        -> io::Result<(Conn, ra::PlayerId, ra::SessionToken, Arena, Rules)> {
+    info!("Connecting to {} over {:?}...", ip, transport);
+
+    let mut conn = match transport {
+        Transport::Tcp => {
+            let mut stream = TcpStream::connect(ip)?;
+            ra::handshake(&mut stream)?;
+            Conn::Tcp(stream)
+        }
+        Transport::Udp => Conn::Udp(UdpPeer::connect(ip)?),
+    };
+
+    conn.send(&ra::ToServerMsg::Login(name.to_string(), session), true)
+        .map_err(to_io_error)?;
+
+    let (id, session, arena, rules) = receive_welcome(&mut conn)?;
+    rules.check()?;
+    Ok((conn, id, session, arena, rules))
+}
+
+fn receive_welcome(conn: &mut Conn)
+                   -> io::Result<(ra::PlayerId, ra::SessionToken, Arena, Rules)> {
+    if let Conn::Udp(ref mut peer) = *conn {
+        // the server only learns about us from our packets, so keep knocking
+        peer.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            peer.flush()?;
+
+            match peer.recv() {
+                Ok(msgs) => {
+                    for data in msgs {
+                        match ra::decode(&data) {
+                            Ok(ra::FromServerMsg::Welcome(id, session, arena, rules)) => {
+                                // wake the listener up now and then to see if
+                                // the server is still there
+                                peer.set_read_timeout(Some(Duration::from_secs(1)))?;
+                                return Ok((id, session, arena, rules));
+                            }
+                            Ok(ra::FromServerMsg::Disconnect { reason }) => {
+                                return Err(io::Error::new(io::ErrorKind::Other, reason));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Err(ref e) if is_timeout(e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "server didn't answer"));
+    }
+
+    match conn.recv().map(|mut msgs| msgs.pop()) {
+        Ok(Some(ra::FromServerMsg::Welcome(id, session, arena, rules))) => {
+            Ok((id, session, arena, rules))
+        }
+        Ok(Some(ra::FromServerMsg::Disconnect { reason })) => {
+            Err(io::Error::new(io::ErrorKind::Other, reason))
+        }
+        Ok(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "server didn't welcome us")),
+        Err(e) => Err(to_io_error(e)),
+    }
 }
 
-fn connect(ip: String) -> TcpStream {
-    println!("Connecting to {}...", ip);
+// runs on its own thread, handing everything from the server to the game loop
+fn listen(mut conn: Conn, sender: Sender<Received>) {
+    // states the server may send deltas against
+    let mut history: VecDeque<GameState> = VecDeque::new();
+
+    loop {
+        // try to read new state from server
+        let msgs = match conn.recv() {
+            Ok(msgs) => msgs,
+            Err(e) => {
+                if let bc::ErrorKind::IoError(ref e) = *e {
+                    if is_timeout(e) && !conn.timed_out() {
+                        continue;
+                    }
+                }
+
+                warn!("Listener thread: Error: {}", e);
+                let reason = format!("Lost connection to the server: {}", e);
+                let _ = sender.send(Received::Lost(reason));
+                return;
+            }
+        };
+
+        for msg in msgs {
+            let received = match msg {
+                ra::FromServerMsg::Update(delta) => {
+                    let gs = match delta.baseline {
+                        Some(tick) => {
+                            match history.iter().find(|gs| gs.tick == tick) {
+                                Some(baseline) => delta.apply(Some(baseline)),
+                                None => {
+                                    warn!(tick = tick; "Listener thread: Error: unknown baseline");
+                                    let reason = "Lost track of the game state".to_string();
+                                    let _ = sender.send(Received::Lost(reason));
+                                    return;
+                                }
+                            }
+                        }
+                        None => delta.apply(None),
+                    };
 
-    match TcpStream::connect(ip) {
-        Ok(stream) => stream,
-        Err(e) => {
-            println!("Failed to connect: {}", e);
-            process::exit(-1);
+                    history.push_back(gs.clone());
+                    if history.len() > ra::SNAPSHOT_HISTORY {
+                        history.pop_front();
+                    }
+
+                    Received::State(gs)
+                }
+                ra::FromServerMsg::Events(events) => Received::Events(events),
+                ra::FromServerMsg::Ping(n) => {
+                    // answered from here so the frame rate doesn't count
+                    // towards the round trip
+                    let pong = ra::ToServerMsg::Pong(n);
+                    if let Err(e) = conn.send(&pong, false) {
+                        warn!("Listener thread: Error: {}", e);
+                    }
+                    continue;
+                }
+                ra::FromServerMsg::Disconnect { reason } => Received::Disconnected(reason),
+                // only ever sent before this thread starts
+                ra::FromServerMsg::Welcome(..) => {
+                    warn!("Listener thread: Error: welcomed twice");
+                    Received::Disconnected("Protocol error: welcomed twice".to_string())
+                }
+            };
+
+            let done = match received {
+                Received::Disconnected(_) => true,
+                _ => false,
+            };
+
+            if sender.send(received).is_err() || done {
+                return;
+            }
         }
     }
 }
 
+// keeps trying to get back into the game as the same player, for about as
+// long as the server keeps players around
+fn reconnect(ip: String,
+             transport: Transport,
+             name: String,
+             session: ra::SessionToken,
+             sender: Sender<Received>) {
+    let mut error = String::new();
+
+    for _ in 0..RECONNECT_ATTEMPTS {
+        thread::sleep(Duration::from_secs(1));
+
+        match join(&ip, transport, &name, Some(session)) {
+            Ok((conn, id, session, arena, rules)) => {
+                let conn_clone = match conn.try_clone() {
+                    Ok(conn_clone) => conn_clone,
+                    Err(e) => {
+                        error = e.to_string();
+                        continue;
+                    }
+                };
+
+                // the game loop has to switch over before any states arrive
+                if sender
+                       .send(Received::Reconnected(conn, id, session, arena, rules))
+                       .is_ok() {
+                    listen(conn_clone, sender);
+                }
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to reconnect: {}", e);
+                error = e.to_string();
+            }
+        }
+    }
+
+    let _ = sender.send(Received::Disconnected(format!("Couldn't reconnect: {}", error)));
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+fn to_io_error(e: bc::Error) -> io::Error {
+    match *e {
+        bc::ErrorKind::IoError(ref e) => io::Error::new(e.kind(), e.to_string()),
+        _ => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+}
+
 struct State {
     game_state: GameState,
+    arena: Arena,
+    rules: Rules,
     player_id: ra::PlayerId,
     window_size: (u32, u32),
     mouse_screen: Vector,
@@ -487,6 +826,30 @@ struct State {
     begin_time: Instant,
     flash: Instant,
     messages: VecDeque<(String, Instant)>,
+    server_ip: String,
+    transport: Transport,
+    username: String,
+    session: ra::SessionToken,
+    // hands the reconnecting thread a way back to the game loop
+    sender: Sender<Received>,
+    // nothing gets sent while this is set
+    reconnecting: bool,
+    input_seq: u32,
+    predicted: VecDeque<PredictedStep>,
+    accumulator: f32,
+    interpolation: ra::Interpolation,
+    // everyone seen so far, players are gone by the time we hear they left
+    names: HashMap<ra::PlayerId, String>,
+    // what gets drawn, other players and bullets a little in the past so
+    // they move smoothly, and our own player as predicted
+    view: GameState,
+}
+
+// one tick of our own movement, run before the server confirmed it
+struct PredictedStep {
+    // the newest input sent when this step ran
+    input: u32,
+    force: Vector,
 }
 
 impl State {
@@ -498,23 +861,130 @@ impl State {
     }
 
     fn player_pos(&self) -> Vector {
-        self.game_state.players[&self.player_id].pos
+        self.game_state
+            .players
+            .get(&self.player_id)
+            .map_or(VEC_ZERO, |p| p.pos)
     }
 
     fn player_dir(&self) -> Vector {
         self.player_dir
     }
 
+    // events can be about players who've left, or whose joining hasn't
+    // reached us yet
+    fn name(&self, id: ra::PlayerId) -> String {
+        self.names
+            .get(&id)
+            .or_else(|| self.game_state.players.get(&id).map(|p| &p.name))
+            .cloned()
+            .unwrap_or_else(|| "someone".to_string())
+    }
+
+    fn input_msg(&mut self, input: ra::Input) -> ra::ToServerMsg {
+        self.input_seq += 1;
+        ra::ToServerMsg::Input(self.input_seq, input)
+    }
+
+    fn send_input(&self, conn: &mut Conn, msg: &ra::ToServerMsg) {
+        if self.reconnecting {
+            return;
+        }
+
+        // a lost press or release would leave a button stuck, everything else
+        // is superseded by the next message anyway
+        let reliable = match *msg {
+            ra::ToServerMsg::Input(_, ra::Input::DirChanged(_)) |
+            ra::ToServerMsg::Ack(_) => false,
+            _ => true,
+        };
+
+        // the listener notices a dead connection and gets us reconnected
+        if let Err(e) = conn.send(msg, reliable) {
+            warn!("Error while sending input: {}", e);
+        }
+    }
+
+    fn predict(&mut self, dt: f32) {
+        let force = ra::move_force(self.buttons_down
+                                       .keys()
+                                       .filter_map(|&b| convert_button(b)),
+                                   &self.rules);
+
+        self.predicted
+            .push_back(PredictedStep {
+                           input: self.input_seq,
+                           force,
+                       });
+        if self.predicted.len() > (MAX_PREDICTED_SECS * self.rules.ticks_per_second) as usize {
+            self.predicted.pop_front();
+        }
+
+        if let Some(p) = self.game_state.players.get_mut(&self.player_id) {
+            if p.health > 0.0 {
+                p.force = force;
+                p.step(&self.arena, dt);
+            }
+        }
+    }
+
+    fn update_view(&mut self) {
+        let mut view = self.interpolation
+            .state()
+            .unwrap_or_else(|| self.game_state.clone());
+
+        if let Some(p) = self.game_state.players.get(&self.player_id) {
+            view.players.insert(self.player_id, p.clone());
+        }
+
+        self.view = view;
+    }
+
+    // the server's state is behind ours, so replay the steps it hasn't seen
+    // yet on top of it
+    fn reconcile(&mut self) {
+        let p = match self.game_state.players.get_mut(&self.player_id) {
+            Some(p) => p,
+            None => return,
+        };
+
+        if p.health == 0.0 {
+            self.predicted.clear();
+            return;
+        }
+
+        // the server has run this many ticks with its last input held, which
+        // lines up with our steps from when we sent that input
+        let ran = self.game_state.tick.saturating_sub(p.last_input_tick) as usize;
+        let start = self.predicted
+            .iter()
+            .position(|s| s.input >= p.last_input)
+            .unwrap_or(self.predicted.len());
+        let same_input = self.predicted
+            .iter()
+            .skip(start)
+            .take_while(|s| s.input == p.last_input)
+            .count();
+
+        self.predicted.drain(..start + ran.min(same_input));
+
+        let dt = self.rules.dt();
+        for s in &self.predicted {
+            p.force = s.force;
+            p.step(&self.arena, dt);
+        }
+    }
+
     fn draw(&mut self, ctx: &mut RenderContext) {
-        for y in 0..LOGO_HEIGHT {
-            for x in 0..LOGO_WIDTH {
-                let color = logo(x, y);
+        for y in 0..self.arena.height() {
+            for x in 0..self.arena.width() {
+                let color = tile_color(self.arena.tile(x, y));
                 let r = rectangle::square(x as f64, y as f64, 1.0);
                 rectangle(color, r, ctx.transforms.tracking, ctx.g);
             }
         }
 
-        for &ra::Bullet { pos, vel, pid, .. } in &self.game_state.bullets {
+        for &ra::Bullet { pos, vel, pid, .. } in &self.view.bullets {
             let mut color = ra::color_for_id(pid);
             color[0] = (color[0] * 2.0).min(1.0);
             color[1] = (color[1] * 2.0).min(1.0);
@@ -540,7 +1010,7 @@ impl State {
                 health,
                 ref name,
                 ..
-            } in self.game_state.players.values() {
+            } in self.view.players.values() {
 
             let color = ra::color_for_id(id);
             let (px, py) = as_f64s(pos);
@@ -583,9 +1053,9 @@ impl State {
             p.draw(self, ctx);
         }
 
-        for y in 0..LOGO_HEIGHT {
-            for x in 0..LOGO_WIDTH {
-                if logo(x, y) == GREEN {
+        for y in 0..self.arena.height() {
+            for x in 0..self.arena.width() {
+                if self.arena.tile(x, y) == Tile::Glow {
                     graphics::image(&ctx.assets.glow,
                                     ctx.transforms
                                         .tracking
@@ -635,13 +1105,13 @@ impl State {
         let mut scores = vec![];
 
         for p in self.game_state.players.values() {
-            scores.push((&p.name, p.score));
+            scores.push((&p.name, p.score, p.rtt));
         }
 
-        scores.sort_by(|&(n1, s1), &(n2, s2)| s2.cmp(&s1).then(n1.cmp(n2)));
+        scores.sort_by(|&(n1, s1, _), &(n2, s2, _)| s2.cmp(&s1).then(n1.cmp(n2)));
 
-        for (i, (name, score)) in scores.into_iter().enumerate().take(10) {
-            let msg = format!("{} | {}", name, score);
+        for (i, (name, score, rtt)) in scores.into_iter().enumerate().take(10) {
+            let msg = format!("{} | {} | {}ms", name, score, (rtt * 1000.0) as u32);
             let xo = 10.0;
             let yo = 10.0;
             let rw = ctx.assets.cache.width(size, &msg);
@@ -662,7 +1132,10 @@ impl State {
         self.messages
             .retain(|&(_, i)| i.elapsed().into_secs() < duration);
 
-        let health = self.game_state.players[&self.player_id].health / PLAYER_HEALTH;
+        let health = self.game_state
+            .players
+            .get(&self.player_id)
+            .map_or(0.0, |p| p.health) / self.rules.player_health;
         let rw = 300.0;
         let rh = 30.0;
         let yo = 10.0;
@@ -743,20 +1216,13 @@ struct RenderContext<'a, 'b: 'a> {
     assets: &'a mut Assets<'b>,
 }
 
-fn logo(x: usize, y: usize) -> Color {
-    assert!(x < LOGO_WIDTH);
-    assert!(y < LOGO_HEIGHT);
-
-    let row = &LOGO[y];
-    let pixel = row.bytes().nth(x).unwrap();
-
-    match pixel {
-        b'b' => BLACK,
-        b'w' => color::grey(0.8),
-        b'i' => WHITE,
-        b'g' => GREEN,
-        b'f' => color::grey(0.18),
-        _ => unreachable!(),
+fn tile_color(tile: Tile) -> Color {
+    match tile {
+        Tile::Wall => BLACK,
+        Tile::Edge => color::grey(0.8),
+        Tile::Void => WHITE,
+        Tile::Glow => GREEN,
+        Tile::Floor => color::grey(0.18),
     }
 }
 
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+
+use ludomath::vec2d::*;
+
+use {Bullet, BulletId, GameState, Player, PlayerId, VectorDef};
+
+// how many past states the server keeps around as baselines, and so how many
+// the client has to keep to apply deltas against them
+pub const SNAPSHOT_HISTORY: usize = 64;
+
+/// The game state at `tick`, encoded as the changes since `baseline`, a
+/// state the client has acknowledged. Without a baseline everything is
+/// sent.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Delta {
+    pub tick: u64,
+    pub baseline: Option<u64>,
+    pub players: Vec<(PlayerId, Vec<PlayerField>)>,
+    pub players_removed: Vec<PlayerId>,
+    pub bullets_spawned: Vec<Bullet>,
+    pub bullets_moved: Vec<BulletMoved>,
+    pub bullets_removed: Vec<BulletId>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum PlayerField {
+    Name(String),
+    Dir(#[serde(with = "VectorDef")] Vector),
+    Pos(#[serde(with = "VectorDef")] Vector),
+    Vel(#[serde(with = "VectorDef")] Vector),
+    Force(#[serde(with = "VectorDef")] Vector),
+    Health(f32),
+    RespawnTimer(f32),
+    Score(u32),
+    LastInput(u32, u64),
+    Rtt(f32),
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct BulletMoved {
+    pub id: BulletId,
+    #[serde(with = "VectorDef")]
+    pub pos: Vector,
+}
+
+impl Delta {
+    pub fn new(baseline: Option<&GameState>, current: &GameState) -> Self {
+        let mut players = vec![];
+        let mut players_removed = vec![];
+
+        for p in current.players.values() {
+            let old = baseline.and_then(|b| b.players.get(&p.id));
+            let fields = diff_player(old, p);
+            if !fields.is_empty() {
+                players.push((p.id, fields));
+            }
+        }
+
+        let mut bullets_spawned = vec![];
+        let mut bullets_moved = vec![];
+        let mut bullets_removed = vec![];
+
+        if let Some(baseline) = baseline {
+            for id in baseline.players.keys() {
+                if !current.players.contains_key(id) {
+                    players_removed.push(*id);
+                }
+            }
+
+            let old_bullets: HashMap<_, _> = baseline.bullets.iter().map(|b| (b.id, b)).collect();
+
+            for b in &current.bullets {
+                match old_bullets.get(&b.id) {
+                    Some(old) if old.pos == b.pos => {}
+                    Some(_) => bullets_moved.push(BulletMoved { id: b.id, pos: b.pos }),
+                    None => bullets_spawned.push(*b),
+                }
+            }
+
+            for b in &baseline.bullets {
+                if !current.bullets.iter().any(|c| c.id == b.id) {
+                    bullets_removed.push(b.id);
+                }
+            }
+        } else {
+            bullets_spawned = current.bullets.clone();
+        }
+
+        Delta {
+            tick: current.tick,
+            baseline: baseline.map(|b| b.tick),
+            players,
+            players_removed,
+            bullets_spawned,
+            bullets_moved,
+            bullets_removed,
+        }
+    }
+
+    // `baseline` must be the state this delta was made against
+    pub fn apply(self, baseline: Option<&GameState>) -> GameState {
+        assert_eq!(self.baseline, baseline.map(|b| b.tick));
+
+        let mut state = match baseline {
+            Some(b) => b.clone(),
+            None => {
+                GameState {
+                    players: HashMap::new(),
+                    bullets: vec![],
+                    events: vec![],
+                    tick: 0,
+                }
+            }
+        };
+
+        state.tick = self.tick;
+        // events are sent on their own, the baseline's are long over
+        state.events.clear();
+
+        for id in self.players_removed {
+            state.players.remove(&id);
+        }
+
+        for (id, fields) in self.players {
+            let p = state
+                .players
+                .entry(id)
+                .or_insert_with(|| Player { id, ..Player::default() });
+
+            for field in fields {
+                match field {
+                    PlayerField::Name(name) => p.name = name,
+                    PlayerField::Dir(dir) => p.dir = dir,
+                    PlayerField::Pos(pos) => p.pos = pos,
+                    PlayerField::Vel(vel) => p.vel = vel,
+                    PlayerField::Force(force) => p.force = force,
+                    PlayerField::Health(health) => p.health = health,
+                    PlayerField::RespawnTimer(t) => p.respawn_timer = t,
+                    PlayerField::Score(score) => p.score = score,
+                    PlayerField::LastInput(seq, tick) => {
+                        p.last_input = seq;
+                        p.last_input_tick = tick;
+                    }
+                    PlayerField::Rtt(rtt) => p.rtt = rtt,
+                }
+            }
+        }
+
+        let removed = self.bullets_removed;
+        state.bullets.retain(|b| !removed.contains(&b.id));
+
+        for moved in self.bullets_moved {
+            if let Some(b) = state.bullets.iter_mut().find(|b| b.id == moved.id) {
+                b.pos = moved.pos;
+            }
+        }
+
+        state.bullets.extend(self.bullets_spawned);
+        state
+    }
+}
+
+fn diff_player(old: Option<&Player>, new: &Player) -> Vec<PlayerField> {
+    let mut fields = vec![];
+
+    if old.map_or(true, |o| o.name != new.name) {
+        fields.push(PlayerField::Name(new.name.clone()));
+    }
+    if old.map_or(true, |o| o.dir != new.dir) {
+        fields.push(PlayerField::Dir(new.dir));
+    }
+    if old.map_or(true, |o| o.pos != new.pos) {
+        fields.push(PlayerField::Pos(new.pos));
+    }
+    if old.map_or(true, |o| o.vel != new.vel) {
+        fields.push(PlayerField::Vel(new.vel));
+    }
+    if old.map_or(true, |o| o.force != new.force) {
+        fields.push(PlayerField::Force(new.force));
+    }
+    if old.map_or(true, |o| o.health != new.health) {
+        fields.push(PlayerField::Health(new.health));
+    }
+    if old.map_or(true, |o| o.respawn_timer != new.respawn_timer) {
+        fields.push(PlayerField::RespawnTimer(new.respawn_timer));
+    }
+    if old.map_or(true, |o| o.score != new.score) {
+        fields.push(PlayerField::Score(new.score));
+    }
+    if old.map_or(true, |o| {
+        o.last_input != new.last_input || o.last_input_tick != new.last_input_tick
+    }) {
+        fields.push(PlayerField::LastInput(new.last_input, new.last_input_tick));
+    }
+    if old.map_or(true, |o| o.rtt != new.rtt) {
+        fields.push(PlayerField::Rtt(new.rtt));
+    }
+
+    fields
+}
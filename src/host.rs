@@ -0,0 +1,826 @@
+use std::mem;
+use std::thread;
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::mpsc::{channel, Receiver, Sender, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use bc;
+use ludomath::rng::Rng;
+use ludomath::vec2d::*;
+use ludomath::consts::*;
+use mio::{Ready, Registration, SetReadiness};
+use mio::net::{TcpListener, UdpSocket};
+
+use {move_force, Arena, Bullet, BulletId, Button, Delta, Event, FromServerMsg, GameState, Input,
+     IntoSecs, LeaveReason, Link, Player, PlayerId, PositionHistory, Rules, SessionToken,
+     ToServerMsg, Transport, SNAPSHOT_HISTORY};
+use listen::{forget_session, Client, FromClient, Listener, Lobby, Outgoing, Sessions};
+
+// a client whose queue stays full for this long gets dropped
+const MAX_SKIPPED_SECS: u32 = 1;
+const PING_INTERVAL_SECS: f32 = 1.0;
+const SHOTS_PER_SEC: u64 = 8;
+// presses and direction changes past this many in a second are ignored,
+// plenty for a mouse that reports every millisecond
+const MAX_INPUTS_PER_SEC: u32 = 1000;
+// past this there'd be a lot of history kept around to let players shoot at
+// where others were a long time ago
+const MAX_REWIND_MS: u32 = 1000;
+
+/// How a server plays, everything but where clients reach it.
+#[derive(Clone)]
+pub struct ServerConfig {
+    pub arena: Arena,
+    // how far back in time shots from lagging players are checked, in
+    // milliseconds
+    pub max_rewind: u32,
+    // seconds without hearing from a client before dropping it
+    pub heartbeat_timeout: f32,
+    // seconds a player who lost their connection can come back within
+    pub reconnect_grace: f32,
+    pub rules: Rules,
+}
+
+impl ServerConfig {
+    pub fn new(arena: Arena) -> Self {
+        ServerConfig {
+            arena,
+            max_rewind: 200,
+            heartbeat_timeout: 10.0,
+            reconnect_grace: 30.0,
+            rules: Rules::default(),
+        }
+    }
+
+    /// Turns down settings a server can't run with, rules included.
+    pub fn check(&self) -> io::Result<()> {
+        let bad = |msg: &str| Err(io::Error::new(io::ErrorKind::InvalidData, msg.to_string()));
+
+        if self.max_rewind > MAX_REWIND_MS {
+            return bad(&format!("max_rewind can't be more than {} milliseconds", MAX_REWIND_MS));
+        }
+
+        if !self.heartbeat_timeout.is_finite() || self.heartbeat_timeout <= 0.0 {
+            return bad("heartbeat_timeout has to be above 0");
+        }
+
+        if !self.reconnect_grace.is_finite() || self.reconnect_grace < 0.0 {
+            return bad("reconnect_grace can't be negative");
+        }
+
+        self.rules.check()
+    }
+}
+
+/// Where the game loop gets the time from. Heartbeat timeouts, reconnect
+/// grace, pings and input limits go by it, so tests can run a server on time
+/// they control. The listeners' login deadlines, write stalls and UDP link
+/// timeouts still go by the system clock.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock { now: Arc::new(Mutex::new(Instant::now())) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// A game, and the clients playing it.
+///
+/// Clients come in through listeners, each serving on its own thread, and
+/// the game moves on one `tick` at a time. `run` ticks at the real rate,
+/// for a server that's all the program does.
+pub struct Server<C: Clock = SystemClock> {
+    clock: C,
+    config: ServerConfig,
+    local_state: LocalState,
+    game_state: GameState,
+    positions: PositionHistory,
+    history: VecDeque<GameState>,
+    // leave events, sent out with the next tick
+    left: Vec<Event>,
+    new_client_sender: Sender<Client>,
+    new_clients: Receiver<Client>,
+    input_sender: Sender<(PlayerId, FromClient)>,
+    inputs: Receiver<(PlayerId, FromClient)>,
+    shut_down: Arc<AtomicBool>,
+    // set to have `run` return
+    stop: Arc<AtomicBool>,
+    // each one's thread, and how to wake it once a tick is queued up
+    listeners: Vec<(JoinHandle<()>, SetReadiness)>,
+}
+
+impl Server<SystemClock> {
+    /// A server taking clients over `transport` at `addr`, as long as
+    /// `config` checks out.
+    pub fn bind<A: ToSocketAddrs>(addr: A,
+                                  transport: Transport,
+                                  config: ServerConfig)
+                                  -> io::Result<Self> {
+        config.check()?;
+
+        let addr = match addr.to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no address")),
+        };
+
+        let mut server = Server::with_clock(config, SystemClock);
+        server.bind_to(&addr, transport)?;
+        Ok(server)
+    }
+}
+
+impl<C: Clock> Server<C> {
+    /// A server with no way in yet, see `listen`.
+    pub fn with_clock(config: ServerConfig, clock: C) -> Self {
+        let (new_client_sender, new_clients) = channel();
+        let (input_sender, inputs) = channel();
+
+        let local_state = LocalState {
+            rng: Rng::new(),
+            spawn_points: config.arena.spawn_points(),
+            arena: config.arena.clone(),
+            clients: HashMap::new(),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            parked: HashMap::new(),
+        };
+
+        let game_state = GameState {
+            players: HashMap::new(),
+            bullets: vec![],
+            events: vec![],
+            tick: 0,
+        };
+
+        // milliseconds times ticks a second can be too much for a u32
+        let rewind_ticks = config.max_rewind as u64 * config.rules.ticks_per_second as u64 / 1000;
+        let positions = PositionHistory::new(rewind_ticks.min(u32::max_value() as u64) as u32);
+
+        Server {
+            clock,
+            config,
+            local_state,
+            game_state,
+            positions,
+            history: VecDeque::new(),
+            left: vec![],
+            new_client_sender,
+            new_clients,
+            input_sender,
+            inputs,
+            shut_down: Arc::new(AtomicBool::new(false)),
+            stop: Arc::new(AtomicBool::new(false)),
+            listeners: vec![],
+        }
+    }
+
+    /// Also takes clients over `transport` at `addr`, returning the address
+    /// it ended up bound to, for when `addr` leaves the port up to the OS.
+    pub fn bind_to(&mut self, addr: &SocketAddr, transport: Transport) -> io::Result<SocketAddr> {
+        match transport {
+            Transport::Tcp => {
+                let listener = TcpListener::bind(addr)?;
+                let addr = listener.local_addr()?;
+                self.listen(listener);
+                Ok(addr)
+            }
+            Transport::Udp => {
+                // a welcome has to get through even when its first packet
+                // is lost
+                let welcome = FromServerMsg::Welcome(PlayerId(0),
+                                                     SessionToken(0, 0),
+                                                     self.config.arena.clone(),
+                                                     self.config.rules.clone());
+                if bc::serialized_size(&welcome) as usize > Link::max_reliable_len() {
+                    let msg = "map is too big to send over UDP, use TCP";
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+                }
+
+                let socket = UdpSocket::bind(addr)?;
+                let addr = socket.local_addr()?;
+                self.listen(socket);
+                Ok(addr)
+            }
+        }
+    }
+
+    /// Lets clients in through `listener`, which serves them on its own
+    /// thread until the server shuts down.
+    pub fn listen<L: Listener + Send + 'static>(&mut self, listener: L) {
+        // the game loop wakes the listener once a tick has been queued up
+        let (waker, wake) = Registration::new2();
+
+        let lobby = Lobby {
+            arena: self.config.arena.clone(),
+            rules: self.config.rules.clone(),
+            heartbeat_timeout: self.config.heartbeat_timeout,
+            sessions: self.local_state.sessions.clone(),
+            input_sender: self.input_sender.clone(),
+            new_client_sender: self.new_client_sender.clone(),
+            shut_down: self.shut_down.clone(),
+            waker,
+            wake: wake.clone(),
+        };
+
+        let handle = thread::spawn(move || listener.serve(lobby));
+        self.listeners.push((handle, wake));
+    }
+
+    pub fn game_state(&self) -> &GameState {
+        &self.game_state
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// For stopping `run` from another thread, or a signal handler.
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle(self.stop.clone())
+    }
+
+    /// Ticks whenever it's time to, until stopped through a `StopHandle`.
+    /// The server is still up afterwards, see `shutdown`.
+    pub fn run(&mut self) {
+        let dt = self.config.rules.dt();
+        // after a stall, run at most this many ticks back to back and drop the rest
+        let max_catch_up = 10;
+        let mut accumulator = 0.0;
+        let mut last_frame = self.clock.now();
+
+        while !self.stop.load(AtomicOrdering::SeqCst) {
+            let now = self.clock.now();
+            accumulator += (now - last_frame).into_secs();
+            last_frame = now;
+
+            if accumulator < dt {
+                let wait = dt - accumulator;
+                thread::sleep(Duration::new(0, (wait * 1_000_000_000.0) as u32));
+                continue;
+            }
+
+            accumulator = (accumulator - dt).min(dt * max_catch_up as f32);
+
+            self.tick();
+        }
+    }
+
+    /// Moves the game on by one tick: takes in what clients sent since the
+    /// last one, and queues up what they're sent back.
+    pub fn tick(&mut self) {
+        let now = self.clock.now();
+
+        let Server {
+            ref config,
+            ref mut local_state,
+            ref mut game_state,
+            ref mut positions,
+            ref mut history,
+            ref mut left,
+            ref new_clients,
+            ref inputs,
+            ref listeners,
+            ..
+        } = *self;
+
+        // update game state
+        let needs_respawn = game_state.update(&config.rules, &local_state.arena, positions);
+
+        game_state.events.extend(left.drain(..));
+
+        for id in needs_respawn {
+            let pos = spawn_player(local_state, game_state);
+            let p = game_state.players.get_mut(&id).unwrap();
+            p.pos = pos;
+            p.health = config.rules.player_health;
+            game_state.events.push(Event::PlayerRespawned(p.id));
+        }
+
+        let mut to_drop = vec![];
+
+        // establish any new clients first, so inputs that come right after
+        // their login aren't taken for a stranger's
+        for Client {
+                player_id,
+                player_name,
+                queue,
+                kick,
+            } in new_clients.try_iter() {
+            let player_state = LocalPlayerState {
+                buttons_down: HashMap::new(),
+                last_ack: None,
+                queue,
+                kick,
+                skipped_updates: 0,
+                last_heard: now,
+                ping: (0, now),
+                last_shot: None,
+                inputs: (0, now),
+                offences: Offences::default(),
+            };
+
+            // a resumed session takes over from the old connection, if
+            // it isn't known to be dead yet, which is closed once its
+            // queue is dropped here
+            local_state.clients.insert(player_id, player_state);
+
+            if game_state.players.contains_key(&player_id) {
+                continue;
+            }
+
+            if let Some((player, _)) = local_state.parked.remove(&player_id) {
+                game_state.events.push(Event::PlayerJoined(player_id));
+                game_state.players.insert(player_id, player);
+                continue;
+            }
+
+            let pos = spawn_player(local_state, game_state);
+
+            let player = Player {
+                id: player_id,
+                name: player_name,
+                pos,
+                health: config.rules.player_health,
+                dir: VEC_RIGHT,
+                force: VEC_ZERO,
+                vel: VEC_ZERO,
+                respawn_timer: 0.0,
+                score: 0,
+                last_input: 0,
+                last_input_tick: 0,
+                rtt: 0.0,
+            };
+
+            game_state.events.push(Event::PlayerJoined(player_id));
+            game_state.players.insert(player_id, player);
+        }
+
+        // get inputs
+        {
+            for (id, msg) in inputs.try_iter() {
+                if let Some(client) = local_state.clients.get_mut(&id) {
+                    client.last_heard = now;
+                }
+
+                // listeners are meant to catch this, but one that doesn't
+                // gets the client kicked, not the server brought down
+                let msg = match msg {
+                    FromClient::Msg(ToServerMsg::Login(..)) => {
+                        FromClient::Violation("already logged in".to_string())
+                    }
+                    msg => msg,
+                };
+
+                let msg = match msg {
+                    FromClient::Msg(msg) => msg,
+                    FromClient::Violation(reason) => {
+                        if let Some(client) = local_state.clients.get(&id) {
+                            warn!(player = id, tick = game_state.tick;
+                                  "Protocol error: {}", reason);
+                            kick(client, &format!("Protocol error: {}", reason));
+                            to_drop.push((id, LeaveReason::Kicked));
+                        }
+                        continue;
+                    }
+                };
+
+                let input = match msg {
+                    ToServerMsg::Input(seq, input) => {
+                        if let Some(player) = game_state.players.get_mut(&id) {
+                            if seq > player.last_input {
+                                player.last_input = seq;
+                                player.last_input_tick = game_state.tick;
+                            }
+                        }
+                        input
+                    }
+                    ToServerMsg::Ack(tick) => {
+                        if let Some(client) = local_state.clients.get_mut(&id) {
+                            if tick <= game_state.tick && Some(tick) > client.last_ack {
+                                client.last_ack = Some(tick);
+                            }
+                        }
+                        continue;
+                    }
+                    ToServerMsg::Logout => {
+                        to_drop.push((id, LeaveReason::Quit));
+                        continue;
+                    }
+                    ToServerMsg::Pong(n) => {
+                        let client = local_state.clients.get(&id);
+                        let player = game_state.players.get_mut(&id);
+                        if let (Some(client), Some(player)) = (client, player) {
+                            let (ping, sent) = client.ping;
+                            if n == ping {
+                                let rtt = (now - sent).into_secs();
+                                player.rtt = if player.rtt == 0.0 {
+                                    rtt
+                                } else {
+                                    player.rtt * 0.875 + rtt * 0.125
+                                };
+                            }
+                        }
+                        continue;
+                    }
+                    // made a violation above
+                    ToServerMsg::Login(..) => continue,
+                };
+
+                let client = match local_state.clients.get_mut(&id) {
+                    Some(client) => client,
+                    None => continue,
+                };
+
+                // a release is always let through, so buttons can't get stuck
+                if let Input::Press(..) | Input::DirChanged(_) = input {
+                    client.inputs.0 += 1;
+                    if client.inputs.0 > MAX_INPUTS_PER_SEC {
+                        client.offences.too_fast += 1;
+                        continue;
+                    }
+                }
+
+                match input {
+                    Input::Press(b, dir, seen_tick) => {
+                        // there's no direction until the mouse has moved, so
+                        // a press with none still counts, just without one
+                        let dir = if dir.x == 0.0 && dir.y == 0.0 {
+                            None
+                        } else {
+                            match sanitize_dir(dir) {
+                                Some(dir) => Some(dir),
+                                None => {
+                                    client.offences.bad_dirs += 1;
+                                    continue;
+                                }
+                            }
+                        };
+
+                        let cooldown = config.rules.ticks_per_second as u64 / SHOTS_PER_SEC;
+                        let ready = client
+                            .last_shot
+                            .map_or(true, |t| game_state.tick >= t + cooldown);
+
+                        if !client.buttons_down.contains_key(&b) {
+                            if let Some(player) = game_state.players.get_mut(&id) {
+                                if let Some(dir) = dir {
+                                    player.dir = dir;
+                                }
+                                client.buttons_down.insert(b, now);
+
+                                if b == Button::LeftMouse && !ready {
+                                    client.offences.early_shots += 1;
+                                } else if b == Button::LeftMouse {
+                                    client.last_shot = Some(game_state.tick);
+
+                                    // spawn bullet
+                                    game_state.events.push(Event::BulletFired(player.pos));
+                                    let rewind = game_state
+                                        .tick
+                                        .saturating_sub(seen_tick)
+                                        .min(positions.max_rewind() as u64);
+                                    let bullet = Bullet::spawn(player,
+                                                               next_bullet_id(),
+                                                               rewind as u32,
+                                                               &config.rules);
+                                    game_state.bullets.push(bullet);
+                                }
+                            }
+                        }
+                    }
+
+                    Input::Release(b) => {
+                        client.buttons_down.remove(&b);
+                    }
+
+                    Input::DirChanged(new_dir) => {
+                        let new_dir = match sanitize_dir(new_dir) {
+                            Some(new_dir) => new_dir,
+                            None => {
+                                client.offences.bad_dirs += 1;
+                                continue;
+                            }
+                        };
+
+                        if let Some(player) = game_state.players.get_mut(&id) {
+                            player.dir = new_dir;
+                        }
+                    }
+                }
+            }
+
+            for (id, lps) in &local_state.clients {
+                if let Some(gs_player) = game_state.players.get_mut(&id) {
+                    gs_player.force = move_force(lps.buttons_down.keys().cloned(), &config.rules);
+                }
+            }
+        }
+
+        // send new state to clients
+        {
+            positions.record(game_state);
+            history.push_back(game_state.clone());
+            if history.len() > SNAPSHOT_HISTORY {
+                history.pop_front();
+            }
+
+            let events = if game_state.events.is_empty() {
+                None
+            } else {
+                let msg = FromServerMsg::Events(game_state.events.clone());
+                Some(Arc::new(bc::serialize(&msg, bc::Infinite).unwrap()))
+            };
+
+            // clients that acked the same tick get the same bytes
+            let mut encoded = HashMap::new();
+
+            for (&id, client) in local_state.clients.iter_mut() {
+                if (now - client.last_heard).into_secs() > config.heartbeat_timeout {
+                    to_drop.push((id, LeaveReason::TimedOut));
+                    continue;
+                }
+
+                if (now - client.inputs.1).into_secs() >= 1.0 {
+                    let Offences {
+                        too_fast,
+                        early_shots,
+                        bad_dirs,
+                    } = mem::replace(&mut client.offences, Offences::default());
+
+                    if too_fast > 0 || early_shots > 0 || bad_dirs > 0 {
+                        warn!(player = id,
+                              tick = game_state.tick,
+                              too_fast = too_fast,
+                              early_shots = early_shots,
+                              bad_dirs = bad_dirs;
+                              "Broke the input limits");
+                    }
+
+                    client.inputs = (0, now);
+                }
+
+                if (now - client.ping.1).into_secs() >= PING_INTERVAL_SECS {
+                    let n = client.ping.0 + 1;
+                    let msg = bc::serialize(&FromServerMsg::Ping(n), bc::Infinite).unwrap();
+                    // no room means no ping this time, the queue check below
+                    // deals with clients that are behind
+                    let _ = client.queue.try_send(Outgoing::Ping(msg));
+                    client.ping = (n, now);
+                }
+
+                let baseline = client
+                    .last_ack
+                    .and_then(|tick| history.iter().find(|gs| gs.tick == tick));
+
+                let update = encoded
+                    .entry(baseline.map(|gs| gs.tick))
+                    .or_insert_with(|| {
+                                        let delta = Delta::new(baseline, game_state);
+                                        let msg = FromServerMsg::Update(delta);
+                                        Arc::new(bc::serialize(&msg, bc::Infinite).unwrap())
+                                    })
+                    .clone();
+
+                let outgoing = Outgoing::Tick {
+                    events: events.clone(),
+                    update,
+                };
+
+                match client.queue.try_send(outgoing) {
+                    Ok(()) => client.skipped_updates = 0,
+                    Err(TrySendError::Full(_)) => {
+                        // a skipped update is made up for by the next delta,
+                        // which is against whatever they acked, but events
+                        // would be lost for good
+                        client.skipped_updates += 1;
+                        let max_skipped = MAX_SKIPPED_SECS * config.rules.ticks_per_second;
+                        if events.is_some() || client.skipped_updates > max_skipped {
+                            kick(client, "Your connection couldn't keep up");
+                            to_drop.push((id, LeaveReason::Kicked));
+                        }
+                    }
+                    // the network thread gave up, so the connection is gone
+                    Err(TrySendError::Disconnected(_)) => {
+                        to_drop.push((id, LeaveReason::TimedOut))
+                    }
+                }
+            }
+
+            for (id, reason) in to_drop {
+                // dropping their queue closes the connection, once whatever
+                // is still in it has been written
+                if local_state.clients.remove(&id).is_some() {
+                    info!(player = id, tick = game_state.tick, reason:? = reason;
+                          "Dropping client");
+
+                    // players who lost their connection are kept around for a
+                    // while in case they reconnect
+                    let player = game_state.players.remove(&id);
+                    match (reason, player) {
+                        (LeaveReason::TimedOut, Some(player)) => {
+                            local_state.parked.insert(id, (player, now));
+                        }
+                        _ => forget_session(&local_state.sessions, id),
+                    }
+
+                    left.push(Event::PlayerLeft(id, reason));
+                }
+            }
+
+            let expired: Vec<_> = local_state
+                .parked
+                .iter()
+                .filter(|&(_, &(_, since))| (now - since).into_secs() > config.reconnect_grace)
+                .map(|(&id, _)| id)
+                .collect();
+
+            for id in expired {
+                local_state.parked.remove(&id);
+                forget_session(&local_state.sessions, id);
+            }
+
+            for &(_, ref wake) in listeners {
+                let _ = wake.set_readiness(Ready::readable());
+            }
+        }
+    }
+
+    /// Tells every client the server is going away, and waits for the
+    /// listeners to see them off. Dropping the server does the same.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        for client in self.local_state.clients.values() {
+            kick(client, "Server shutting down");
+        }
+
+        // dropping their queues closes the connections, once the kick has
+        // been written
+        self.local_state.clients.clear();
+        self.shut_down.store(true, AtomicOrdering::SeqCst);
+
+        for (handle, wake) in self.listeners.drain(..) {
+            let _ = wake.set_readiness(Ready::readable());
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Makes `Server::run` return once it's done with the tick it's on.
+#[derive(Clone)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    pub fn stop(&self) {
+        self.0.store(true, AtomicOrdering::SeqCst);
+    }
+}
+
+impl<C: Clock> Drop for Server<C> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct LocalState {
+    rng: Rng,
+    arena: Arena,
+    spawn_points: Vec<Vector>,
+    clients: HashMap<PlayerId, LocalPlayerState>,
+    sessions: Sessions,
+    // players whose connection dropped, and since when
+    parked: HashMap<PlayerId, (Player, Instant)>,
+}
+
+struct LocalPlayerState {
+    buttons_down: HashMap<Button, Instant>,
+    last_ack: Option<u64>,
+    queue: SyncSender<Outgoing>,
+    kick: SyncSender<Vec<u8>>,
+    skipped_updates: u32,
+    last_heard: Instant,
+    // the last ping sent and when
+    ping: (u32, Instant),
+    // the tick they last fired on
+    last_shot: Option<u64>,
+    // presses and direction changes this second, and when it started
+    inputs: (u32, Instant),
+    offences: Offences,
+}
+
+// what a client did wrong this second, logged once it's over
+#[derive(Default)]
+struct Offences {
+    too_fast: u32,
+    early_shots: u32,
+    bad_dirs: u32,
+}
+
+// a unit vector, or None for something no mouse could have pointed at
+fn sanitize_dir(dir: Vector) -> Option<Vector> {
+    if !dir.x.is_finite() || !dir.y.is_finite() {
+        return None;
+    }
+
+    // brought down to at most 1 first, squaring anything much past 1e19
+    // overflows and the length would come out infinite
+    let scale = dir.x.abs().max(dir.y.abs());
+    if scale < 0.001 {
+        return None;
+    }
+
+    let dir = dir / scale;
+    Some(dir / dir.magnitude())
+}
+
+// tells the client why before their connection is closed, ahead of
+// whatever is still queued for them, so it gets there even when they're
+// kicked for not keeping up
+fn kick(client: &LocalPlayerState, reason: &str) {
+    let msg = FromServerMsg::Disconnect { reason: reason.to_string() };
+    let msg = bc::serialize(&msg, bc::Infinite).unwrap();
+    // only the first reason is kept, they're gone either way
+    let _ = client.kick.try_send(msg);
+}
+
+fn next_bullet_id() -> BulletId {
+    use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+    static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    let id = COUNTER.fetch_add(1, Ordering::SeqCst);
+    BulletId(id as u32)
+}
+
+fn spawn_player(ls: &mut LocalState, gs: &GameState) -> Vector {
+    let cmp_f = |a: f32, b: f32| a.partial_cmp(&b).unwrap_or(Ordering::Equal);
+
+    let empty_tiles = &ls.spawn_points;
+
+    if gs.players.is_empty() {
+        // random tile
+        return empty_tiles[ls.rng.rand_uint(0, empty_tiles.len() as u64) as usize];
+    }
+
+    // tile furthest away from any player
+    let idx = empty_tiles
+        .iter()
+        .cloned()
+        .enumerate()
+        .map(|(i, pos)| {
+                 let shortest = gs.players
+                     .values()
+                     .map(|p| p.pos.dist(pos))
+                     .min_by(|&a, &b| cmp_f(a, b))
+                     .unwrap();
+                 (i, shortest)
+             })
+        .max_by(|&(_, a), &(_, b)| cmp_f(a, b))
+        .map(|(i, _)| i)
+        .unwrap();
+
+    empty_tiles[idx]
+}
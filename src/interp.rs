@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+
+use ludomath::vec2d::*;
+
+use GameState;
+
+// how far past the newest snapshot entities keep moving before they stop
+const MAX_EXTRAPOLATION_SECS: f64 = 0.25;
+// if the render time drifts further than this from where it should be, it
+// jumps instead of catching up gradually
+const MAX_DRIFT_SECS: f64 = 0.25;
+
+/// Snapshots from the server, played back `delay` seconds behind the newest
+/// one so there's usually a snapshot on either side of the time being drawn.
+pub struct Interpolation {
+    snapshots: VecDeque<GameState>,
+    delay: f64,
+    ticks_per_second: u32,
+    // in server seconds, tick / ticks_per_second
+    time: Option<f64>,
+}
+
+impl Interpolation {
+    pub fn new(delay: f32, ticks_per_second: u32) -> Self {
+        Interpolation {
+            snapshots: VecDeque::new(),
+            delay: delay as f64,
+            ticks_per_second,
+            time: None,
+        }
+    }
+
+    // starts over, for a server that may have started over itself
+    pub fn reset(&mut self, ticks_per_second: u32) {
+        self.snapshots.clear();
+        self.ticks_per_second = ticks_per_second;
+        self.time = None;
+    }
+
+    pub fn push(&mut self, gs: GameState) {
+        if !self.snapshots.back().map_or(true, |last| gs.tick > last.tick) {
+            return;
+        }
+
+        let target = self.tick_time(gs.tick) - self.delay;
+        self.time = match self.time {
+            // ease towards the target so the clock doesn't stutter
+            Some(time) if (time - target).abs() < MAX_DRIFT_SECS => {
+                Some(time + (target - time) * 0.1)
+            }
+            _ => Some(target),
+        };
+
+        self.snapshots.push_back(gs);
+    }
+
+    pub fn advance(&mut self, dt: f32) {
+        let time = match self.time {
+            Some(time) => time + dt as f64,
+            None => return,
+        };
+        self.time = Some(time);
+
+        // keep one snapshot at or before the render time
+        while self.snapshots.len() > 1 && self.tick_time(self.snapshots[1].tick) <= time {
+            self.snapshots.pop_front();
+        }
+    }
+
+    // the tick being drawn, rounded down
+    pub fn tick(&self) -> Option<u64> {
+        self.time
+            .map(|time| (time * self.ticks_per_second as f64).max(0.0) as u64)
+    }
+
+    // the state at the render time, None until something has arrived
+    pub fn state(&self) -> Option<GameState> {
+        let time = match self.time {
+            Some(time) => time,
+            None => return None,
+        };
+
+        let from = match self.snapshots.front() {
+            Some(from) => from,
+            None => return None,
+        };
+
+        let to = match self.snapshots.get(1) {
+            Some(to) => to,
+            None => return Some(extrapolate(from, time - self.tick_time(from.tick))),
+        };
+
+        let (from_time, to_time) = (self.tick_time(from.tick), self.tick_time(to.tick));
+        let t = ((time - from_time) / (to_time - from_time))
+            .max(0.0)
+            .min(1.0) as f32;
+
+        let mut state = to.clone();
+
+        for p in state.players.values_mut() {
+            if let Some(old) = from.players.get(&p.id) {
+                // skip teleports, like respawns
+                if p.health > 0.0 && old.health > 0.0 {
+                    p.pos = lerp(old.pos, p.pos, t);
+
+                    let dir = lerp(old.dir, p.dir, t);
+                    if dir.magnitude() > 0.001 {
+                        p.dir = dir.normalize();
+                    }
+                }
+            }
+        }
+
+        // bullets that are gone by `to` stay where `from` had them, new ones
+        // show up once the render time reaches `to`
+        state.bullets = from.bullets.clone();
+        for b in &mut state.bullets {
+            if let Some(new) = to.bullets.iter().find(|n| n.id == b.id) {
+                b.pos = lerp(b.pos, new.pos, t);
+            }
+        }
+
+        Some(state)
+    }
+
+    fn tick_time(&self, tick: u64) -> f64 {
+        tick as f64 / self.ticks_per_second as f64
+    }
+}
+
+fn extrapolate(gs: &GameState, ahead: f64) -> GameState {
+    let dt = ahead.max(0.0).min(MAX_EXTRAPOLATION_SECS) as f32;
+    let mut state = gs.clone();
+
+    for p in state.players.values_mut() {
+        if p.health > 0.0 {
+            p.pos += p.vel * dt;
+        }
+    }
+
+    for b in &mut state.bullets {
+        b.pos += b.vel * dt;
+    }
+
+    state
+}
+
+
+fn lerp(a: Vector, b: Vector, t: f32) -> Vector {
+    a + (b - a) * t
+}
@@ -2,39 +2,50 @@
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate bincode as bc;
 extern crate hsl;
+#[macro_use]
+extern crate log;
 extern crate ludomath;
+extern crate mio;
+extern crate rand;
 
 use std::time::Duration;
 use std::collections::HashMap;
 
 use ludomath::vec2d::*;
-
-pub const LOGO: &[&str] = &["bbbbbbbbbbbb",
-                            "bwwwwwwwwwwb",
-                            "bwffffffffwb",
-                            "bwgfgfgfffwb",
-                            "bwffffffffwb",
-                            "bwfggfggffwb",
-                            "bwffffffffwb",
-                            "bwffffffffwb",
-                            "bwwwwwwwwwwb",
-                            "bbbbffffbbbb",
-                            "iiiibffbiiii",
-                            "ibbbffffbbbi",
-                            "bfbwfwfwbwfb",
-                            "bfwfwbwfwffb",
-                            "bbbbbbbbbbbb"];
-
-pub const LOGO_WIDTH: usize = 12;
-pub const LOGO_HEIGHT: usize = 15;
+use rand::{OsRng, Rng};
+
+mod arena;
+mod delta;
+mod host;
+mod interp;
+mod listen;
+mod logging;
+mod net;
+mod rewind;
+mod rules;
+mod wire;
+
+pub use arena::{Arena, ArenaError, Tile, Walls};
+pub use delta::{BulletMoved, Delta, PlayerField, SNAPSHOT_HISTORY};
+pub use host::{Clock, ManualClock, Server, ServerConfig, StopHandle, SystemClock};
+pub use interp::Interpolation;
+pub use listen::{FromClient, Listener, Lobby, Outbox, Outgoing};
+pub use logging::{init_logging, LogFilter, LogFormat};
+pub use net::{Conn, Link, Transport, UdpPeer};
+pub use rewind::PositionHistory;
+pub use rules::Rules;
+pub use wire::{check_name, check_preamble, decode, decode_bounded, encode_frame, handshake,
+               preamble, read_frame, take_frame, write_frame, write_frame_bytes, Message, MAGIC,
+               MAX_FRAME_LEN, MAX_NAME_LEN, MAX_TO_SERVER_LEN, PREAMBLE_LEN, PROTOCOL_VERSION};
 
 pub const PLAYER_RADIUS: f32 = 0.2;
 pub const BULLET_RADIUS: f32 = 0.05;
 
-pub const PLAYER_HEALTH: f32 = 100.0;
-pub const MAX_DAMAGE: f32 = 10.0;
-pub const RESPAWN_TIME: f32 = 2.0;
+// velocity decays by a factor of e^-VELOCITY_DAMPING every second, which is
+// about 0.9 per tick at the default 120 ticks per second
+pub const VELOCITY_DAMPING: f32 = 12.64;
 
 #[derive(Copy, Clone)]
 pub struct CSquare {
@@ -85,36 +96,179 @@ impl CCircle {
     }
 
     pub fn intersects_square(self, s: &CSquare) -> bool {
-        let s_center = s.top_left + (s.bottom_right - s.top_left) * 0.5;
-        let v = (s_center - self.center).normalize();
-        let outer_point = self.center + self.radius * v;
-        s.contains(outer_point)
+        self.contact_square(s).is_some()
     }
-}
 
-pub fn collision_boxes() -> Vec<CSquare> {
-    assert!(LOGO.iter().all(|r| r.len() == LOGO_WIDTH));
-    assert_eq!(LOGO.len(), LOGO_HEIGHT);
+    // normal points out of the square, towards the circle
+    pub fn contact_square(self, s: &CSquare) -> Option<Contact> {
+        let closest = Vector::new(self.center.x.max(s.top_left.x).min(s.bottom_right.x),
+                                  self.center.y.max(s.top_left.y).min(s.bottom_right.y));
+        let offset = self.center - closest;
+        let dist = offset.magnitude();
 
-    let mut boxes = vec![];
+        if dist >= self.radius {
+            return None;
+        }
+
+        if dist > 0.0 {
+            return Some(Contact {
+                            normal: offset / dist,
+                            depth: self.radius - dist,
+                        });
+        }
 
-    for y in 0..LOGO_HEIGHT {
-        for x in 0..LOGO_WIDTH {
-            let row = &LOGO[y];
-            let pixel = row.bytes().nth(x).unwrap();
+        // center is inside the square, push out through the nearest side
+        let sides = [(self.center.x - s.top_left.x, Vector::new(-1.0, 0.0)),
+                     (s.bottom_right.x - self.center.x, Vector::new(1.0, 0.0)),
+                     (self.center.y - s.top_left.y, Vector::new(0.0, -1.0)),
+                     (s.bottom_right.y - self.center.y, Vector::new(0.0, 1.0))];
+
+        let mut nearest = sides[0];
+        for &side in &sides[1..] {
+            if side.0 < nearest.0 {
+                nearest = side;
+            }
+        }
+
+        Some(Contact {
+                 normal: nearest.1,
+                 depth: self.radius + nearest.0,
+             })
+    }
+
+    // first hit while moving by `motion`, ignoring anything behind us
+    pub fn sweep_circle(self, motion: Vector, other: CCircle) -> Option<Hit> {
+        let radius = self.radius + other.radius;
+        let offset = self.center - other.center;
+        let c = offset.dot(offset) - radius * radius;
+
+        if c <= 0.0 {
+            // already touching
+            let normal = if offset.magnitude() > 0.0 {
+                offset.normalize()
+            } else if motion.magnitude() > 0.0 {
+                -motion.normalize()
+            } else {
+                // right on top of each other and not moving, any way out
+                // will do
+                Vector::new(0.0, -1.0)
+            };
+
+            return Some(Hit {
+                            time: 0.0,
+                            point: other.center + normal * other.radius,
+                            normal,
+                        });
+        }
+
+        let a = motion.dot(motion);
+        let b = 2.0 * motion.dot(offset);
+        let discriminant = b * b - 4.0 * a * c;
+
+        if a == 0.0 || b >= 0.0 || discriminant < 0.0 {
+            return None;
+        }
+
+        let time = (-b - discriminant.sqrt()) / (2.0 * a);
+        if time > 1.0 {
+            return None;
+        }
+
+        let normal = (self.center + motion * time - other.center) / radius;
+
+        Some(Hit {
+                 time,
+                 point: other.center + normal * other.radius,
+                 normal,
+             })
+    }
 
-            match pixel {
-                b'b' | b'g' | b'i' => {
-                    let pos = Vector::new(x as f32, y as f32);
-                    boxes.push(CSquare::new(pos, 1.0));
+    pub fn sweep_square(self, motion: Vector, s: &CSquare) -> Option<Hit> {
+        if let Some(c) = self.contact_square(s) {
+            return Some(Hit {
+                            time: 0.0,
+                            point: self.center - c.normal * (self.radius - c.depth),
+                            normal: c.normal,
+                        });
+        }
+
+        // slab test against the square grown by our radius
+        let r = self.radius;
+        let axes = [(self.center.x, motion.x, s.top_left.x - r, s.bottom_right.x + r),
+                    (self.center.y, motion.y, s.top_left.y - r, s.bottom_right.y + r)];
+
+        let mut enter = ::std::f32::NEG_INFINITY;
+        let mut exit = ::std::f32::INFINITY;
+        let mut normal = Vector::default();
+
+        for (i, &(p, d, lo, hi)) in axes.iter().enumerate() {
+            if d == 0.0 {
+                if p < lo || p > hi {
+                    return None;
                 }
-                b'w' | b'f' => {}
-                _ => unreachable!(),
+                continue;
+            }
+
+            let (t0, t1, sign) = if d > 0.0 {
+                ((lo - p) / d, (hi - p) / d, -1.0)
+            } else {
+                ((hi - p) / d, (lo - p) / d, 1.0)
+            };
+
+            if t0 > enter {
+                enter = t0;
+                normal = if i == 0 {
+                    Vector::new(sign, 0.0)
+                } else {
+                    Vector::new(0.0, sign)
+                };
+            }
+            exit = exit.min(t1);
+        }
+
+        if enter > exit || enter > 1.0 || exit < 0.0 {
+            return None;
+        }
+
+        let center = self.center + motion * enter.max(0.0);
+        let side = |v: f32, lo: f32, hi: f32| if v < lo {
+            Some(lo)
+        } else if v > hi {
+            Some(hi)
+        } else {
+            None
+        };
+
+        // the grown square has rounded corners, which are only hit if we hit
+        // the circle around the corner itself
+        match (side(center.x, s.top_left.x, s.bottom_right.x),
+               side(center.y, s.top_left.y, s.bottom_right.y)) {
+            (Some(x), Some(y)) => self.sweep_circle(motion, CCircle::new(Vector::new(x, y), 0.0)),
+            _ => {
+                Some(Hit {
+                         time: enter,
+                         point: center - normal * r,
+                         normal,
+                     })
             }
         }
     }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Contact {
+    pub normal: Vector,
+    pub depth: f32,
+}
 
-    boxes
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Hit {
+    // fraction of the movement done before impact
+    pub time: f32,
+    #[serde(with = "VectorDef")]
+    pub point: Vector,
+    #[serde(with = "VectorDef")]
+    pub normal: Vector,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -122,82 +276,106 @@ pub struct GameState {
     pub players: HashMap<PlayerId, Player>,
     pub bullets: Vec<Bullet>,
     pub events: Vec<Event>,
+    // number of updates simulated so far
+    pub tick: u64,
 }
 
 impl GameState {
     // returns players to respawn
-    pub fn update(&mut self, collision_boxes: &[CSquare], dt: f32) -> Vec<PlayerId> {
+    pub fn update(&mut self,
+                  rules: &Rules,
+                  arena: &Arena,
+                  positions: &PositionHistory)
+                  -> Vec<PlayerId> {
+        let dt = rules.dt();
         let mut needs_respawn = vec![];
         let mut scored = vec![];
         self.events.clear();
+        self.tick += 1;
 
         // 'players: for i in 0..self.players.len() {
         //     let p = self.players[i].;
         // }        
 
-        'players: for p in self.players.values_mut() {
+        for p in self.players.values_mut() {
             if p.health == 0.0 && p.respawn_timer > 0.0 {
                 p.respawn_timer = (p.respawn_timer - dt).max(0.0);
                 if p.respawn_timer == 0.0 {
                     needs_respawn.push(p.id);
                 }
-                continue;
             }
+        }
 
-            let p_bounds = CCircle::new(p.pos, PLAYER_RADIUS);
+        // bullets are swept along their whole path, so they can't skip past
+        // thin walls or players on long ticks
+        for i in (0..self.bullets.len()).rev() {
+            let mut b = self.bullets[i];
+            let wall_hit = b.sweep_walls(arena, dt);
+            let mut player_hit: Option<(PlayerId, Hit)> = None;
 
-            for i in (0..self.bullets.len()).rev() {
-                if self.bullets[i].pid == p.id {
+            for p in self.players.values() {
+                if p.id == b.pid || p.health == 0.0 {
                     continue;
                 }
 
-                let b_bounds = CCircle::new(self.bullets[i].pos, BULLET_RADIUS);
-
-                if b_bounds.intersects(p_bounds) {
-                    let b = self.bullets.remove(i);
-                    let f = calc_damage(&b, p);
-                    let d = f * MAX_DAMAGE;
-                    p.health = (p.health - d).max(0.0);
-                    self.events.push(Event::BulletHitPlayer(b, p.id, f));
+                let bounds = CCircle::new(b.pos, BULLET_RADIUS);
+                let target = CCircle::new(b.target_pos(p, self.tick, positions), PLAYER_RADIUS);
 
-                    if p.health == 0.0 {
-                        p.respawn_timer = RESPAWN_TIME;
-                        scored.push(b.pid);
-                        self.events.push(Event::PlayerDied(p.id, b.pid));
-                        continue 'players;
+                if let Some(hit) = bounds.sweep_circle(b.vel * dt, target) {
+                    if player_hit.map_or(true, |(_, first)| hit.time < first.time) {
+                        player_hit = Some((p.id, hit));
                     }
                 }
             }
 
-            for cb in collision_boxes {
-                if p_bounds.intersects_square(cb) {
-                    let wc = cb.top_left + Vector::new(0.5, 0.5);
-                    let overlap = p.pos - wc;
-                    p.force += overlap * 20000.0 * dt;
+            // a wall in front of the player shields them
+            let player_hit = match (player_hit, wall_hit) {
+                (Some((_, p)), Some(w)) if w.time < p.time => None,
+                (player_hit, _) => player_hit,
+            };
+
+            if let Some((id, hit)) = player_hit {
+                self.bullets.remove(i);
+                b.pos += b.vel * dt * hit.time;
+
+                let p = self.players.get_mut(&id).unwrap();
+                let f = calc_damage(&b, b.target_pos(p, self.tick, positions));
+                let d = f * rules.max_damage;
+                p.health = (p.health - d).max(0.0);
+                self.events.push(Event::BulletHitPlayer(b, p.id, f, hit));
+
+                if p.health == 0.0 {
+                    p.respawn_timer = rules.respawn_time;
+                    scored.push(b.pid);
+                    self.events.push(Event::PlayerDied(p.id, b.pid));
+                }
+            } else if let Some(hit) = wall_hit {
+                self.bullets.remove(i);
+                b.pos += b.vel * dt * hit.time;
+                self.events.push(Event::BulletHitWall(b, hit));
+            } else {
+                let b = &mut self.bullets[i];
+                b.pos += b.vel * dt;
+
+                // escaped through a hole in the map
+                if !arena.bounds().contains(b.pos) {
+                    self.bullets.remove(i);
                 }
             }
+        }
 
-            p.vel += p.force * dt;
-
-            // Critical that vel doesn't go denormal/too small!
-            // I found that out the hard way after much hardship and long suffering
-            if p.vel.magnitude() < 0.0000001 {
-                p.vel = Vector::default();
+        for p in self.players.values_mut() {
+            if p.health == 0.0 {
+                continue;
             }
 
-            p.vel = p.vel.magnitude().min(40.0) * p.vel.normalize() * 0.9;
-            p.pos += p.vel * dt;
+            p.step(arena, dt);
         }
 
+        // the shooter may have left while their bullet was on its way
         for id in scored {
-            let p = self.players.get_mut(&id).unwrap();
-            p.score += 1;
-        }
-
-        for i in (0..self.bullets.len()).rev() {
-            if self.bullets[i].update(collision_boxes, dt) {
-                let b = self.bullets.remove(i);
-                self.events.push(Event::BulletHitWall(b));
+            if let Some(p) = self.players.get_mut(&id) {
+                p.score += 1;
             }
         }
 
@@ -207,14 +385,22 @@ impl GameState {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Event {
-    BulletHitWall(Bullet),
-    BulletHitPlayer(Bullet, PlayerId, f32),
+    // bullets are at the position where they hit
+    BulletHitWall(Bullet, Hit),
+    BulletHitPlayer(Bullet, PlayerId, f32, Hit),
     BulletFired(#[serde(with = "VectorDef")] Vector),
     // who died and who killed them
     PlayerDied(PlayerId, PlayerId),
     PlayerRespawned(PlayerId),
     PlayerJoined(PlayerId),
-    PlayerLeft(String),
+    PlayerLeft(PlayerId, LeaveReason),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum LeaveReason {
+    Quit,
+    TimedOut,
+    Kicked,
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
@@ -224,28 +410,60 @@ pub struct Bullet {
     #[serde(with = "VectorDef")]
     pub vel: Vector,
     pub pid: PlayerId,
+    pub id: BulletId,
+    // how many ticks behind the shooter was seeing things, players are hit
+    // where they were that long ago
+    pub rewind: u32,
 }
 
 impl Bullet {
-    pub fn spawn(player: &Player) -> Self {
+    pub fn spawn(player: &Player, id: BulletId, rewind: u32, rules: &Rules) -> Self {
         Bullet {
+            id,
             pid: player.id,
             pos: player.pos + player.dir * PLAYER_RADIUS,
-            vel: player.dir * 10.0,
+            vel: player.dir * rules.bullet_speed,
+            rewind,
         }
     }
 
-    // returns if dead
-    pub fn update(&mut self, collision_boxes: &[CSquare], dt: f32) -> bool {
+    // where `player` is to this bullet during update `tick`
+    fn target_pos(&self, player: &Player, tick: u64, positions: &PositionHistory) -> Vector {
+        if self.rewind == 0 {
+            return player.pos;
+        }
+
+        // players haven't moved yet this tick, so they're where they were
+        // at the end of the last one
+        (tick - 1)
+            .checked_sub(self.rewind as u64)
+            .and_then(|t| positions.position(t, player.id))
+            .unwrap_or(player.pos)
+    }
+
+    // first wall hit on the way to where the bullet will be after `dt`
+    pub fn sweep_walls(&self, arena: &Arena, dt: f32) -> Option<Hit> {
+        let motion = self.vel * dt;
+        let end = self.pos + motion;
+        let area = CSquare {
+            top_left: Vector::new(self.pos.x.min(end.x), self.pos.y.min(end.y)) -
+                      Vector::new(BULLET_RADIUS, BULLET_RADIUS),
+            bottom_right: Vector::new(self.pos.x.max(end.x), self.pos.y.max(end.y)) +
+                          Vector::new(BULLET_RADIUS, BULLET_RADIUS),
+        };
+
         let bounds = CCircle::new(self.pos, BULLET_RADIUS);
-        for cb in collision_boxes {
-            if bounds.intersects_square(cb) {
-                return true;
+        let mut first: Option<Hit> = None;
+
+        for cb in arena.walls_near(area) {
+            if let Some(hit) = bounds.sweep_square(motion, &cb) {
+                if first.map_or(true, |f| hit.time < f.time) {
+                    first = Some(hit);
+                }
             }
         }
 
-        self.pos += self.vel * dt;
-        self.pos.magnitude() > 20.0
+        first
     }
 }
 
@@ -264,10 +482,63 @@ pub struct Player {
     pub health: f32,
     pub respawn_timer: f32,
     pub score: u32,
+    // the last input the server has handled from this player, and the tick
+    // it was handled on
+    pub last_input: u32,
+    pub last_input_tick: u64,
+    // smoothed round trip time to the server, in seconds
+    pub rtt: f32,
+}
+
+impl Player {
+    // movement only, the client runs this to predict its own player
+    pub fn step(&mut self, arena: &Arena, dt: f32) {
+        self.vel += self.force * dt;
+
+        // Critical that vel doesn't go denormal/too small!
+        // I found that out the hard way after much hardship and long suffering
+        if self.vel.magnitude() < 0.0000001 {
+            self.vel = Vector::default();
+        }
+
+        self.vel = self.vel.magnitude().min(40.0) * self.vel.normalize() *
+                   (-VELOCITY_DAMPING * dt).exp();
+        self.pos += self.vel * dt;
+
+        // push out of walls and drop the velocity going into them, so
+        // players slide along walls instead of bouncing off
+        for cb in arena.walls_near(CSquare::new_centered(self.pos, PLAYER_RADIUS)) {
+            if let Some(c) = CCircle::new(self.pos, PLAYER_RADIUS).contact_square(&cb) {
+                self.pos += c.normal * c.depth;
+
+                let into = self.vel.dot(c.normal);
+                if into < 0.0 {
+                    self.vel = self.vel - c.normal * into;
+                }
+            }
+        }
+    }
+}
+
+// the force the movement keys held down push a player with
+pub fn move_force<I: IntoIterator<Item = Button>>(buttons: I, rules: &Rules) -> Vector {
+    let mut force = Vector::default();
+
+    for b in buttons {
+        match b {
+            Button::A => force.x -= rules.move_force,
+            Button::D => force.x += rules.move_force,
+            Button::W => force.y -= rules.move_force,
+            Button::S => force.y += rules.move_force,
+            Button::LeftMouse => {}
+        }
+    }
+
+    force
 }
 
-pub fn calc_damage(bullet: &Bullet, player: &Player) -> f32 {
-    let f = (bullet.pos - player.pos)
+pub fn calc_damage(bullet: &Bullet, target: Vector) -> f32 {
+    let f = (bullet.pos - target)
         .normalize()
         .dot(bullet.vel.normalize())
         .abs();
@@ -276,22 +547,39 @@ pub fn calc_damage(bullet: &Bullet, player: &Player) -> f32 {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum FromServerMsg {
-    // player id
-    Welcome(PlayerId),
-    Update(GameState),
+    // player id, the token to reconnect with, and the arena being played
+    // and the rules it's played by
+    Welcome(PlayerId, SessionToken, Arena, Rules),
+    Update(Delta),
+    // events are taken out of updates and sent reliably on their own, so
+    // updates can be skipped or lost
+    Events(Vec<Event>),
+    // the last message before the server closes the connection
+    Disconnect { reason: String },
+    // to be answered with a Pong right away
+    Ping(u32),
 }
 
+// the server knows who sent these from the connection they came in on
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ToServerMsg {
-    Login(PlayerId, String),
-    Input(PlayerId, Input),
+    // name, and the session to resume when reconnecting
+    Login(String, Option<SessionToken>),
+    // inputs are numbered from 1, so the server can say which it has handled
+    Input(u32, Input),
+    // last tick received, so the server can send deltas against it
+    Ack(u64),
+    Logout,
+    Pong(u32),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Input {
+    // also the tick the client was drawing, so shots can be lag compensated
     Press(Button,
           #[serde(with = "VectorDef")]
-          Vector),
+          Vector,
+          u64),
     Release(Button),
 
     DirChanged(#[serde(with = "VectorDef")]
@@ -310,6 +598,22 @@ pub enum Button {
 #[derive(Default, Hash, PartialOrd, Ord, PartialEq, Eq, Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct PlayerId(pub u32);
 
+#[derive(Default, Hash, PartialOrd, Ord, PartialEq, Eq, Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct BulletId(pub u32);
+
+/// Handed out when joining, and presented when reconnecting to get the same
+/// player back.
+#[derive(Hash, PartialEq, Eq, Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct SessionToken(u64, u64);
+
+impl SessionToken {
+    pub fn generate() -> Self {
+        // they're as good as a password, so they come straight from the OS
+        let mut rng = OsRng::new().expect("failed to get randomness from the OS");
+        SessionToken(rng.next_u64(), rng.next_u64())
+    }
+}
+
 pub trait IntoSecs {
     fn into_secs(&self) -> f32;
 }
@@ -0,0 +1,903 @@
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::{Entry, RandomState};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, Sender, SyncSender, TryRecvError};
+use std::time::{Duration, Instant};
+
+use bc;
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio::net::{TcpListener, TcpStream, UdpSocket};
+
+use {check_name, check_preamble, decode, encode_frame, preamble, take_frame, Arena,
+     FromServerMsg, IntoSecs, Link, Message, PlayerId, Rules, SessionToken, ToServerMsg, MAGIC,
+     PREAMBLE_LEN};
+
+// ticks of updates waiting to be written to a client
+const WRITE_QUEUE_LEN: usize = 32;
+// to finish the handshake and log in, before the connection is closed
+const LOGIN_TIMEOUT_SECS: u64 = 5;
+// a TCP client that doesn't take anything written to it for this long is
+// closed
+const MAX_WRITE_STALL_SECS: f32 = 10.0;
+// after an error accepting clients, most likely from running out of file
+// descriptors, how long before trying again
+const ACCEPT_BACKOFF_MS: u64 = 100;
+// bytes read from a TCP client each time round, so one sending flat out
+// can't keep the rest waiting
+const READ_BUDGET: usize = 16 * 1024;
+// after the server shuts down, how long listeners keep trying to tell
+// clients so
+const MAX_SHUTDOWN_SECS: f32 = 1.0;
+// a cookie handed to a UDP client is good for between one and two of these
+const COOKIE_SECS: u64 = 10;
+
+/// A way for clients to reach a server. `serve` runs on its own thread,
+/// letting clients in through the lobby and passing on what they send, and
+/// returns once the lobby says the server has shut down.
+pub trait Listener {
+    fn serve(self, lobby: Lobby);
+}
+
+impl Listener for TcpListener {
+    fn serve(self, lobby: Lobby) {
+        serve_tcp(self, lobby)
+    }
+}
+
+impl Listener for UdpSocket {
+    fn serve(self, lobby: Lobby) {
+        serve_udp(self, lobby)
+    }
+}
+
+/// What the game loop queues up for a client, already encoded.
+pub enum Outgoing {
+    // one tick's worth of messages
+    Tick {
+        events: Option<Arc<Vec<u8>>>,
+        update: Arc<Vec<u8>>,
+    },
+    Ping(Vec<u8>),
+    // sent last, then the connection is closed
+    Disconnect(Vec<u8>),
+}
+
+/// What a listener hands to the game loop, along with the player the
+/// connection belongs to.
+pub enum FromClient {
+    Msg(ToServerMsg),
+    // the client broke the protocol and gets dropped, with the reason why
+    Violation(String),
+}
+
+pub struct Client {
+    pub player_id: PlayerId,
+    pub player_name: String,
+    // the connection is closed once this is dropped
+    pub queue: SyncSender<Outgoing>,
+    // for the Disconnect telling them why they're kicked, which can't wait
+    // for room in the queue
+    pub kick: SyncSender<Vec<u8>>,
+}
+
+/// The listener's end of what the game loop has for a client.
+pub struct Outbox {
+    queue: Receiver<Outgoing>,
+    kick: Receiver<Vec<u8>>,
+}
+
+impl Outbox {
+    /// The next thing to send them. A kick goes ahead of whatever is still
+    /// queued, which is never sent.
+    pub fn try_recv(&self) -> Result<Outgoing, TryRecvError> {
+        if let Ok(msg) = self.kick.try_recv() {
+            return Ok(Outgoing::Disconnect(msg));
+        }
+
+        self.queue.try_recv()
+    }
+}
+
+// which player each session token belongs to, shared between the listeners
+// and the game loop, which forgets sessions once they can't be resumed
+pub type Sessions = Arc<Mutex<HashMap<SessionToken, PlayerId>>>;
+
+// the player and token for a login, and whether it resumes a session, or
+// None if there's no room for another player
+fn login(sessions: &Sessions,
+         session: Option<SessionToken>,
+         max_players: u32)
+         -> Option<(PlayerId, SessionToken, bool)> {
+    let mut sessions = sessions.lock().unwrap();
+
+    if let Some(token) = session {
+        if let Some(&id) = sessions.get(&token) {
+            return Some((id, token, true));
+        }
+    }
+
+    // every session is a player in the game, or one who can still come back
+    if sessions.len() >= max_players as usize {
+        return None;
+    }
+
+    let id = next_player_id();
+    let token = SessionToken::generate();
+    sessions.insert(token, id);
+    Some((id, token, false))
+}
+
+pub fn forget_session(sessions: &Sessions, id: PlayerId) {
+    sessions.lock().unwrap().retain(|_, &mut pid| pid != id);
+}
+
+/// Everything a listener needs to let clients in and pass their messages on.
+pub struct Lobby {
+    pub(crate) arena: Arena,
+    pub(crate) rules: Rules,
+    // seconds without hearing from a client before giving up on them
+    pub(crate) heartbeat_timeout: f32,
+    pub(crate) sessions: Sessions,
+    pub(crate) input_sender: Sender<(PlayerId, FromClient)>,
+    pub(crate) new_client_sender: Sender<Client>,
+    pub(crate) shut_down: Arc<AtomicBool>,
+    // readable once the game loop has queued up a tick
+    pub(crate) waker: Registration,
+    pub(crate) wake: SetReadiness,
+}
+
+impl Lobby {
+    /// Hands a new player to the game loop, returning their welcome and the
+    /// outbox of what to send them after it, or the message turning them
+    /// away. Both are encoded messages, ready to go out.
+    pub fn join<A: fmt::Display>(&self,
+                                 name: String,
+                                 session: Option<SessionToken>,
+                                 peer: A)
+                                 -> Result<(Vec<u8>, PlayerId, Outbox), Vec<u8>> {
+        let login = if self.is_shut_down() {
+            Err("Server shutting down".to_string())
+        } else {
+            check_name(&name)
+                .map_err(|e| format!("Bad name: {}", e))
+                .and_then(|()| {
+                              login(&self.sessions, session, self.rules.max_players)
+                                  .ok_or_else(|| "Server full".to_string())
+                          })
+        };
+
+        let (id, token, resumed) = match login {
+            Ok(login) => login,
+            Err(reason) => {
+                info!(peer:% = peer; "Rejecting client: {}", reason);
+                let msg = FromServerMsg::Disconnect { reason };
+                return Err(bc::serialize(&msg, bc::Infinite).unwrap());
+            }
+        };
+        info!(player = id, peer:% = peer, name = &name[..], resumed = resumed;
+              "Logging in client");
+
+        // writing happens between ticks, so a slow client can't hold up the
+        // game loop
+        let (queue, outgoing) = sync_channel(WRITE_QUEUE_LEN);
+        let (kick, kicked) = sync_channel(1);
+        let client = Client {
+            player_id: id,
+            player_name: name,
+            queue,
+            kick,
+        };
+        let _ = self.new_client_sender.send(client);
+
+        let outbox = Outbox {
+            queue: outgoing,
+            kick: kicked,
+        };
+
+        // send client a player id
+        let msg = FromServerMsg::Welcome(id, token, self.arena.clone(), self.rules.clone());
+        Ok((bc::serialize(&msg, bc::Infinite).unwrap(), id, outbox))
+    }
+
+    pub fn send(&self, id: PlayerId, msg: FromClient) {
+        // nothing to do if the game loop is gone
+        let _ = self.input_sender.send((id, msg));
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+
+    /// Has `poll` wake up under `token` whenever the game loop has queued up
+    /// a tick, until `woken` is called.
+    pub fn register(&self, poll: &Poll, token: Token) -> io::Result<()> {
+        poll.register(&self.waker, token, Ready::readable(), PollOpt::level())
+    }
+
+    pub fn woken(&self) {
+        let _ = self.wake.set_readiness(Ready::empty());
+    }
+}
+
+// the listening socket, and the game loop saying there's something to write
+const SOCKET: Token = Token(0);
+const WAKER: Token = Token(1);
+
+// one thread does all the reading and writing for every client, whenever
+// their sockets are ready, and writes whatever the game loop has queued up
+// each time it's woken
+fn serve_tcp(listener: TcpListener, lobby: Lobby) {
+    let poll = Poll::new().unwrap();
+    // level triggered, so anyone left waiting after an error gets another go
+    poll.register(&listener, SOCKET, Ready::readable(), PollOpt::level())
+        .unwrap();
+    lobby.register(&poll, WAKER).unwrap();
+
+    let mut clients: HashMap<Token, TcpClient> = HashMap::new();
+    let mut next_token = 2;
+    let mut events = Events::with_capacity(1024);
+    // since when the server's been shut down
+    let mut stopping = None;
+    // clients with more to read than they got through last time round
+    let mut unread = HashSet::new();
+    // until when accepting is put off after an error
+    let mut accept_paused = None;
+
+    loop {
+        // no waiting around while there's reading left to do
+        let timeout = if unread.is_empty() { 100 } else { 0 };
+        if let Err(e) = poll.poll(&mut events, Some(Duration::from_millis(timeout))) {
+            error!("Error polling: {}", e);
+            continue;
+        }
+
+        if let Some(until) = accept_paused {
+            if Instant::now() >= until && !lobby.is_shut_down() {
+                let opts = PollOpt::level();
+                match poll.register(&listener, SOCKET, Ready::readable(), opts) {
+                    Ok(()) => accept_paused = None,
+                    Err(e) => {
+                        error!("Error listening for clients: {}", e);
+                        accept_paused = Some(Instant::now() +
+                                             Duration::from_millis(ACCEPT_BACKOFF_MS));
+                    }
+                }
+            }
+        }
+
+        for event in &events {
+            match event.token() {
+                SOCKET => {
+                    loop {
+                        let (stream, addr) = match listener.accept() {
+                            Ok(accepted) => accepted,
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => {
+                                // whatever it is won't have gone away by the
+                                // next poll, so rather than spin, stop
+                                // listening for a bit
+                                error!("Error accepting client: {}", e);
+                                let _ = poll.deregister(&listener);
+                                accept_paused = Some(Instant::now() +
+                                                     Duration::from_millis(ACCEPT_BACKOFF_MS));
+                                break;
+                            }
+                        };
+
+                        debug!(peer:% = addr; "New client connected");
+
+                        let token = Token(next_token);
+                        next_token += 1;
+
+                        let interest = Ready::readable() | Ready::writable();
+                        if let Err(e) = poll.register(&stream, token, interest, PollOpt::edge()) {
+                            warn!(peer:% = addr; "Error accepting client: {}", e);
+                            continue;
+                        }
+
+                        let deadline = Instant::now() + Duration::from_secs(LOGIN_TIMEOUT_SECS);
+                        clients.insert(token, TcpClient::new(stream, addr, deadline));
+                    }
+                }
+                WAKER => lobby.woken(),
+                token => {
+                    if event.readiness().is_readable() {
+                        unread.insert(token);
+                    }
+                }
+            }
+        }
+
+        // a turn each
+        let turn: Vec<Token> = unread.drain().collect();
+        for token in turn {
+            let read = match clients.get_mut(&token) {
+                Some(client) => client.read(&lobby),
+                None => continue,
+            };
+
+            match read {
+                Ok(true) => {
+                    unread.insert(token);
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    if let Some(client) = clients.remove(&token) {
+                        client.log_error(&e);
+                        client.close();
+                    }
+                }
+            }
+        }
+
+        let shut_down = lobby.is_shut_down();
+        let mut closed = vec![];
+
+        for (&token, client) in &mut clients {
+            match client.flush(shut_down) {
+                Ok(true) => closed.push(token),
+                Ok(false) => {}
+                Err(e) => {
+                    client.log_error(&e);
+                    closed.push(token);
+                }
+            }
+        }
+
+        for token in closed {
+            if let Some(client) = clients.remove(&token) {
+                client.close();
+            }
+        }
+
+        if shut_down {
+            let since = *stopping.get_or_insert_with(|| {
+                // nobody new gets in
+                let _ = poll.deregister(&listener);
+                Instant::now()
+            });
+
+            if clients.is_empty() || since.elapsed().into_secs() > MAX_SHUTDOWN_SECS {
+                return;
+            }
+        }
+    }
+}
+
+struct TcpClient {
+    stream: TcpStream,
+    addr: SocketAddr,
+    stage: TcpStage,
+    // read, but not a whole message yet
+    incoming: Vec<u8>,
+    // waiting for the socket to take it
+    outgoing: Vec<u8>,
+    // when the socket last took anything
+    last_write: Instant,
+}
+
+enum TcpStage {
+    // until their preamble arrives, with the deadline to log in by
+    Handshake(Instant),
+    // until Login arrives
+    Login(Instant),
+    Playing(PlayerId, Outbox),
+    // writing out what's left, then the connection is closed
+    Closing,
+}
+
+impl TcpClient {
+    fn new(stream: TcpStream, addr: SocketAddr, deadline: Instant) -> Self {
+        TcpClient {
+            stream,
+            addr,
+            stage: TcpStage::Handshake(deadline),
+            incoming: vec![],
+            // their side checks this, like ours checks theirs
+            outgoing: preamble().to_vec(),
+            last_write: Instant::now(),
+        }
+    }
+
+    // reads up to READ_BUDGET bytes, acting on each message as it's
+    // complete, and says whether there's more to read
+    fn read(&mut self, lobby: &Lobby) -> io::Result<bool> {
+        let mut buf = [0; 4096];
+        let mut read = 0;
+
+        while read < READ_BUDGET {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"))
+                }
+                Ok(n) => {
+                    read += n;
+                    self.incoming.extend_from_slice(&buf[..n]);
+                    self.handle(lobby)?;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn handle(&mut self, lobby: &Lobby) -> io::Result<()> {
+        loop {
+            let next = match self.stage {
+                TcpStage::Handshake(deadline) => {
+                    if self.incoming.len() < PREAMBLE_LEN {
+                        return Ok(());
+                    }
+
+                    check_preamble(&self.incoming[..PREAMBLE_LEN])?;
+                    self.incoming.drain(..PREAMBLE_LEN);
+                    TcpStage::Login(deadline)
+                }
+                TcpStage::Login(_) => {
+                    let (name, session) = match take_frame(&mut self.incoming) {
+                        Ok(Some(ToServerMsg::Login(name, session))) => (name, session),
+                        Ok(None) => return Ok(()),
+                        msg => {
+                            let msg = format!("expected Login, got {:?}", msg);
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+                        }
+                    };
+
+                    match lobby.join(name, session, self.addr) {
+                        Ok((welcome, id, queue)) => {
+                            self.outgoing.extend(encode_frame(&welcome)?);
+                            TcpStage::Playing(id, queue)
+                        }
+                        Err(rejection) => {
+                            self.outgoing.extend(encode_frame(&rejection)?);
+                            TcpStage::Closing
+                        }
+                    }
+                }
+                TcpStage::Playing(id, _) => {
+                    let msg = match take_frame(&mut self.incoming) {
+                        Ok(Some(msg)) => msg,
+                        Ok(None) => return Ok(()),
+                        Err(e) => {
+                            // they'll be kicked, and there's no telling where
+                            // the next message starts anyway
+                            self.incoming.clear();
+                            lobby.send(id, FromClient::Violation(e.to_string()));
+                            return Ok(());
+                        }
+                    };
+
+                    match msg {
+                        ToServerMsg::Login(..) => {
+                            self.incoming.clear();
+                            let reason = "already logged in".to_string();
+                            lobby.send(id, FromClient::Violation(reason));
+                            return Ok(());
+                        }
+                        ToServerMsg::Logout => {
+                            lobby.send(id, FromClient::Msg(msg));
+                            TcpStage::Closing
+                        }
+                        msg => {
+                            lobby.send(id, FromClient::Msg(msg));
+                            continue;
+                        }
+                    }
+                }
+                // nothing they say matters anymore
+                TcpStage::Closing => {
+                    self.incoming.clear();
+                    return Ok(());
+                }
+            };
+
+            self.stage = next;
+        }
+    }
+
+    // writes what the socket will take, true once it's all written and the
+    // connection can be closed
+    fn flush(&mut self, shut_down: bool) -> io::Result<bool> {
+        match self.stage {
+            TcpStage::Handshake(_) |
+            TcpStage::Login(_) if shut_down => {
+                return Err(io::Error::new(io::ErrorKind::Other, "server shutting down"));
+            }
+            TcpStage::Handshake(deadline) |
+            TcpStage::Login(deadline) if Instant::now() > deadline => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "login timed out"));
+            }
+            _ => {}
+        }
+
+        // more is only taken from the queue once the last lot is written, so
+        // a client that can't keep up fills it and the game loop finds out
+        if self.outgoing.is_empty() {
+            self.take_queued()?;
+            self.last_write = Instant::now();
+        }
+
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed")),
+                Ok(n) => {
+                    self.outgoing.drain(..n);
+                    self.last_write = Instant::now();
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        // the game loop gives up on them well before this, but can't tell us
+        // so while their queue is still full
+        if self.last_write.elapsed().into_secs() > MAX_WRITE_STALL_SECS {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "stopped reading"));
+        }
+
+        match self.stage {
+            TcpStage::Closing => Ok(self.outgoing.is_empty()),
+            _ => Ok(false),
+        }
+    }
+
+    fn take_queued(&mut self) -> io::Result<()> {
+        let mut closing = false;
+
+        if let TcpStage::Playing(_, ref queue) = self.stage {
+            loop {
+                match queue.try_recv() {
+                    Ok(Outgoing::Tick { events, update }) => {
+                        if let Some(events) = events {
+                            self.outgoing.extend(encode_frame(&events)?);
+                        }
+                        self.outgoing.extend(encode_frame(&update)?);
+                    }
+                    Ok(Outgoing::Ping(msg)) => self.outgoing.extend(encode_frame(&msg)?),
+                    Ok(Outgoing::Disconnect(msg)) => {
+                        self.outgoing.extend(encode_frame(&msg)?);
+                        closing = true;
+                        break;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    // the game loop is done with them
+                    Err(TryRecvError::Disconnected) => {
+                        closing = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if closing {
+            self.stage = TcpStage::Closing;
+        }
+
+        Ok(())
+    }
+
+    // dropping the stream deregisters it
+    fn close(self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    fn log_error(&self, e: &io::Error) {
+        match self.stage {
+            TcpStage::Handshake(_) |
+            TcpStage::Login(_) => info!(peer:% = self.addr; "Rejecting client: {}", e),
+            TcpStage::Playing(id, _) => {
+                info!(player = id, peer:% = self.addr; "Lost client: {}", e)
+            }
+            TcpStage::Closing => {}
+        }
+    }
+}
+
+struct UdpClient {
+    // known once they log in
+    id: Option<PlayerId>,
+    link: Link,
+    // gone once the game loop is done with them
+    queue: Option<Outbox>,
+}
+
+// Cookies for UDP clients, made from their address and the time so
+// nothing has to be kept to check one. A client only gets one by receiving
+// packets at the address it claims.
+struct Cookies {
+    key: RandomState,
+    started: Instant,
+}
+
+impl Cookies {
+    fn new() -> Self {
+        Cookies {
+            key: RandomState::new(),
+            started: Instant::now(),
+        }
+    }
+
+    fn make(&self, addr: SocketAddr) -> u64 {
+        self.make_at(addr, self.window())
+    }
+
+    // the one from the window before is still good, so a cookie handed out
+    // right before the next one starts doesn't go stale on the way
+    fn check(&self, addr: SocketAddr, cookie: u64) -> bool {
+        let window = self.window();
+        cookie == self.make_at(addr, window) ||
+        (window > 0 && cookie == self.make_at(addr, window - 1))
+    }
+
+    fn window(&self) -> u64 {
+        self.started.elapsed().as_secs() / COOKIE_SECS
+    }
+
+    fn make_at(&self, addr: SocketAddr, window: u64) -> u64 {
+        let mut hasher = self.key.build_hasher();
+        addr.hash(&mut hasher);
+        window.hash(&mut hasher);
+        // 0 is what a client sends before it has one
+        hasher.finish().max(1)
+    }
+}
+
+// UDP has no connections, so clients are told apart by address
+fn serve_udp(socket: UdpSocket, lobby: Lobby) {
+    let poll = Poll::new().unwrap();
+    poll.register(&socket, SOCKET, Ready::readable(), PollOpt::level())
+        .unwrap();
+    lobby.register(&poll, WAKER).unwrap();
+
+    let mut clients: HashMap<SocketAddr, UdpClient> = HashMap::new();
+    let cookies = Cookies::new();
+    let mut buf = vec![0; 65536];
+    let mut last_prune = Instant::now();
+    let mut events = Events::with_capacity(16);
+    let mut stopping = None;
+
+    loop {
+        if let Err(e) = poll.poll(&mut events, Some(Duration::from_millis(100))) {
+            error!("Error polling: {}", e);
+            continue;
+        }
+
+        for event in &events {
+            match event.token() {
+                SOCKET => {
+                    loop {
+                        match socket.recv_from(&mut buf) {
+                            Ok((len, addr)) => {
+                                let datagram = &buf[..len];
+                                receive_udp(&socket, datagram, addr, &mut clients, &cookies, &lobby)
+                            }
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => warn!("Error receiving packet: {}", e),
+                        }
+                    }
+                }
+                WAKER => lobby.woken(),
+                _ => {}
+            }
+        }
+
+        // forget clients that went quiet, the game loop drops them as well
+        if last_prune.elapsed().into_secs() >= 1.0 {
+            clients.retain(|_, c| !c.link.timed_out());
+            last_prune = Instant::now();
+        }
+
+        for (addr, client) in &mut clients {
+            client.flush(&socket, addr);
+        }
+
+        if lobby.is_shut_down() {
+            let since = *stopping.get_or_insert(Instant::now());
+
+            // keep resending the kicks until they're acked
+            let mut waiting = false;
+            for (addr, client) in &mut clients {
+                if client.link.has_unacked() {
+                    send_packets(&socket, client.link.packets(None), addr);
+                    waiting = true;
+                }
+            }
+
+            if !waiting || since.elapsed().into_secs() > MAX_SHUTDOWN_SECS {
+                return;
+            }
+        }
+    }
+}
+
+fn receive_udp(socket: &UdpSocket,
+               datagram: &[u8],
+               addr: SocketAddr,
+               clients: &mut HashMap<SocketAddr, UdpClient>,
+               cookies: &Cookies,
+               lobby: &Lobby) {
+    if let Err(e) = check_preamble(datagram) {
+        debug!(peer:% = addr; "Rejecting packet: {}", e);
+
+        // tell clients of another version what we speak, so they can say so
+        if datagram.starts_with(&MAGIC) {
+            let _ = socket.send_to(&preamble(), &addr);
+        }
+        return;
+    }
+
+    let (client, msgs) = match clients.entry(addr) {
+        Entry::Occupied(entry) => {
+            let client = entry.into_mut();
+            match client.link.receive(datagram) {
+                Ok(msgs) => (client, msgs),
+                Err(e) => {
+                    warn!(player = client.id, peer:% = addr; "Bad packet: {}", e);
+                    return;
+                }
+            }
+        }
+        Entry::Vacant(entry) => {
+            // source addresses can be made up, so a stranger has to show it
+            // gets packets sent to its address before anything else. The
+            // cookie sent back is never bigger than what they sent, so
+            // this can't be used to flood someone else with more than
+            // they're sent
+            let cookie = Link::cookie_of(datagram);
+            if !cookie.map_or(false, |cookie| cookies.check(addr, cookie)) {
+                let challenge = Link::challenge(cookies.make(addr));
+                if cookie.is_some() && challenge.len() <= datagram.len() {
+                    debug!(peer:% = addr; "Sending a cookie to a stranger");
+                    send_packet(socket, challenge, &addr);
+                }
+                return;
+            }
+
+            let mut link = Link::new(ToServerMsg::max_len() as usize, lobby.heartbeat_timeout);
+            let msgs = match link.receive(datagram) {
+                Ok(msgs) => msgs,
+                Err(e) => {
+                    debug!(peer:% = addr; "Bad packet: {}", e);
+                    return;
+                }
+            };
+
+            // nothing is kept for one until it asks to log in
+            let login = msgs.iter()
+                .any(|data| match decode(data) {
+                         Ok(ToServerMsg::Login(..)) => true,
+                         _ => false,
+                     });
+            if !login {
+                debug!(peer:% = addr; "Ignoring packet from a stranger");
+                return;
+            }
+
+            debug!(peer:% = addr; "New client connected");
+
+            let client = UdpClient {
+                id: None,
+                link,
+                queue: None,
+            };
+            (entry.insert(client), msgs)
+        }
+    };
+
+    // until they're welcomed, the game loop doesn't send anything to them
+    let reply = client.id.is_none();
+
+    for data in msgs {
+        let msg = match decode(&data) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!(player = client.id, peer:% = addr; "Bad message: {}", e);
+                if let Some(id) = client.id {
+                    lobby.send(id, FromClient::Violation(e.to_string()));
+                }
+                continue;
+            }
+        };
+
+        match msg {
+            ToServerMsg::Login(name, session) => {
+                if let Some(id) = client.id {
+                    lobby.send(id, FromClient::Violation("already logged in".to_string()));
+                    continue;
+                }
+
+                match lobby.join(name, session, addr) {
+                    Ok((welcome, id, queue)) => {
+                        client.link.queue_reliable(welcome);
+                        client.id = Some(id);
+                        client.queue = Some(queue);
+                    }
+                    Err(rejection) => client.link.queue_reliable(rejection),
+                }
+            }
+            _ => {
+                match client.id {
+                    Some(id) => lobby.send(id, FromClient::Msg(msg)),
+                    None => {
+                        warn!(peer:% = addr; "Protocol error: expected Login, got {:?}", msg)
+                    }
+                }
+            }
+        }
+    }
+
+    if reply {
+        send_packets(socket, client.link.packets(None), &addr);
+    }
+}
+
+impl UdpClient {
+    // sends whatever the game loop queued up for them
+    fn flush(&mut self, socket: &UdpSocket, addr: &SocketAddr) {
+        let mut closed = false;
+
+        if let Some(ref queue) = self.queue {
+            loop {
+                match queue.try_recv() {
+                    Ok(Outgoing::Tick { events, update }) => {
+                        if let Some(events) = events {
+                            self.link.queue_reliable(events.to_vec());
+                        }
+                        send_packets(socket, self.link.packets(Some(update.to_vec())), addr);
+                    }
+                    Ok(Outgoing::Ping(msg)) => {
+                        send_packets(socket, self.link.packets(Some(msg)), addr)
+                    }
+                    Ok(Outgoing::Disconnect(msg)) => {
+                        self.link.queue_reliable(msg);
+                        send_packets(socket, self.link.packets(None), addr);
+                        closed = true;
+                        break;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    // the game loop is done with them
+                    Err(TryRecvError::Disconnected) => {
+                        closed = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if closed {
+            self.queue = None;
+        }
+    }
+}
+
+fn send_packets(socket: &UdpSocket, packets: Vec<Vec<u8>>, addr: &SocketAddr) {
+    for packet in packets {
+        send_packet(socket, packet, addr);
+    }
+}
+
+fn send_packet(socket: &UdpSocket, packet: Vec<u8>, addr: &SocketAddr) {
+    match socket.send_to(&packet, addr) {
+        Ok(_) => {}
+        // it's as good as lost on the way
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+        Err(e) => warn!(peer:% = addr; "Error sending packet: {}", e),
+    }
+}
+
+fn next_player_id() -> PlayerId {
+    use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+    static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    let id = COUNTER.fetch_add(1, Ordering::SeqCst);
+    PlayerId(id as u32)
+}
@@ -0,0 +1,267 @@
+// Leveled logging for the server and client, a backend for the `log` crate.
+// Every line says how serious it is and which module it came from, and can
+// carry fields like the player, peer address and tick it's about:
+//
+//     info!(player = id, peer:% = addr; "Logging in client {}", name);
+//
+// What gets through is set by a filter like "info,recurse_arena::listen=debug",
+// a default level and then levels for modules and everything under them.
+// Lines come out as text, or as one JSON object each for feeding into
+// something else.
+
+use std::cmp;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{self, Level, LevelFilter, Log, Metadata, Record};
+use log::kv::{self, Key, ToValue, Value, VisitSource, VisitValue};
+
+use PlayerId;
+
+/// Which lines get logged. The most specific module given decides, a level
+/// of `off` silences a module.
+#[derive(Clone, Debug)]
+pub struct LogFilter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    pub fn enabled(&self, module: &str, level: Level) -> bool {
+        let mut max = self.default;
+        let mut matched = 0;
+
+        for &(ref prefix, module_max) in &self.modules {
+            let under = module == prefix ||
+                        (module.starts_with(&prefix[..]) &&
+                         module[prefix.len()..].starts_with("::"));
+            if under && prefix.len() >= matched {
+                max = module_max;
+                matched = prefix.len();
+            }
+        }
+
+        level <= max
+    }
+
+    // the most anything gets logged at, so the macros can skip the rest
+    // without asking
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, cmp::max)
+    }
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        LogFilter {
+            default: LevelFilter::Info,
+            modules: vec![],
+        }
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let parse_level = |s: &str| {
+            s.trim()
+                .parse::<LevelFilter>()
+                .map_err(|_| format!("unknown log level {:?}", s))
+        };
+
+        let mut filter = LogFilter::default();
+
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let mut split = part.splitn(2, '=');
+            let first = split.next().unwrap().trim();
+            match split.next() {
+                Some(level) => filter.modules.push((first.to_string(), parse_level(level)?)),
+                None => filter.default = parse_level(first)?,
+            }
+        }
+
+        Ok(filter)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    // one JSON object a line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}', expected text or json", s)),
+        }
+    }
+}
+
+impl ToValue for PlayerId {
+    fn to_value<'a>(&'a self) -> Value<'a> {
+        self.0.to_value()
+    }
+}
+
+struct Logger {
+    filter: LogFilter,
+    format: LogFormat,
+}
+
+/// Sends log lines to stdout for the rest of the program. Only the first
+/// call does anything, until then nothing is logged.
+pub fn init_logging(filter: LogFilter, format: LogFormat) {
+    let max = filter.max_level();
+    if log::set_boxed_logger(Box::new(Logger { filter, format })).is_ok() {
+        log::set_max_level(max);
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata.target(), metadata.level())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let ts = format!("{}.{:03}",
+                         since_epoch.as_secs(),
+                         since_epoch.subsec_nanos() / 1_000_000);
+
+        let mut line = String::new();
+        match self.format {
+            LogFormat::Text => {
+                line.push_str(&format!("{} {:5} {}: {}",
+                                       ts,
+                                       record.level(),
+                                       record.target(),
+                                       record.args()));
+                let _ = record.key_values().visit(&mut TextFields(&mut line));
+            }
+            LogFormat::Json => {
+                line.push_str(&format!("{{\"ts\":{},\"level\":\"{}\",\"module\":{},\"msg\":{}",
+                                       ts,
+                                       record.level().as_str().to_lowercase(),
+                                       json_string(record.target()),
+                                       json_string(&record.args().to_string())));
+                let _ = record.key_values().visit(&mut JsonFields(&mut line));
+                line.push('}');
+            }
+        }
+        line.push('\n');
+
+        // one write a line, so lines from different threads don't interleave
+        let stdout = io::stdout();
+        let _ = stdout.lock().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+// ` key=value` for each field, quoted where the value would otherwise run
+// into the next one or be mistaken for more fields
+struct TextFields<'a>(&'a mut String);
+
+impl<'a, 'kvs> VisitSource<'kvs> for TextFields<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = value.to_string();
+        let quote = value.is_empty() ||
+                    value.contains(|c: char| c.is_whitespace() || c == '=' || c == '"');
+        if quote {
+            self.0.push_str(&format!(" {}={:?}", key, value));
+        } else {
+            self.0.push_str(&format!(" {}={}", key, value));
+        }
+        Ok(())
+    }
+}
+
+// `,"key":value` for each field
+struct JsonFields<'a>(&'a mut String);
+
+impl<'a, 'kvs> VisitSource<'kvs> for JsonFields<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let mut json = JsonValue(String::new());
+        value.visit(&mut json)?;
+        self.0
+            .push_str(&format!(",{}:{}", json_string(key.as_str()), json.0));
+        Ok(())
+    }
+}
+
+// numbers, bools and nulls as themselves, everything else as a string
+struct JsonValue(String);
+
+impl<'v> VisitValue<'v> for JsonValue {
+    fn visit_any(&mut self, value: Value) -> Result<(), kv::Error> {
+        self.0 = json_string(&value.to_string());
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0 = "null".to_string();
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.0 = value.to_string();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.0 = value.to_string();
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        self.0 = if value.is_finite() {
+            value.to_string()
+        } else {
+            json_string(&value.to_string())
+        };
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.0 = value.to_string();
+        Ok(())
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,662 @@
+use std::io;
+use std::mem;
+use std::str::FromStr;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use bc;
+use serde::Serialize;
+
+use {FromServerMsg, IntoSecs};
+use wire::{self, Message};
+
+// unacked reliable messages are resent this often
+const RESEND_INTERVAL_SECS: f32 = 0.1;
+// a client's link that hasn't heard from the server for this long is dead,
+// the server goes by its heartbeat timeout
+const LINK_TIMEOUT_SECS: f32 = 10.0;
+const MAX_DATAGRAM: usize = 65536;
+// small enough to fit in what any link on the way can carry whole, as
+// fragments can get dropped or not reassembled
+const MAX_PACKET_LEN: usize = 1200;
+// the data in one piece of a message, leaving plenty of room in a packet
+// for its header and the piece's own
+const MAX_PIECE_LEN: usize = 1024;
+// how far past the next reliable message due others can arrive, and how
+// many bytes of them can be waiting for it, so they can't pile up without
+// end
+const MAX_OUT_OF_ORDER: u32 = 1024;
+const MAX_OUT_OF_ORDER_BYTES: usize = 256 * 1024;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "tcp" => Ok(Transport::Tcp),
+            "udp" => Ok(Transport::Udp),
+            _ => Err(format!("unknown transport '{}', expected tcp or udp", s)),
+        }
+    }
+}
+
+/// Sequencing and acks on top of UDP datagrams.
+///
+/// Every packet carries its sequence number plus the sequence numbers of
+/// the last 33 packets received from the other side. Reliable messages are
+/// repeated in outgoing packets until a packet holding them is acked, and
+/// are delivered in order. Unreliable messages are only delivered if
+/// nothing newer has arrived already.
+///
+/// Packets are kept small enough to get anywhere without being fragmented
+/// on the way, messages that don't fit in one are split into pieces. A
+/// reliable message's pieces are each sent and acked like a message of
+/// their own, an unreliable one is lost if any of its pieces are.
+///
+/// Packets also carry the cookie the server handed the client, so the
+/// server knows the client really is at the address its packets say they
+/// came from. Each side sends back the last one it got, 0 until then.
+pub struct Link {
+    seq: u32,
+    remote_seq: Option<u32>,
+    ack_bits: u32,
+    next_message_id: u32,
+    next_unreliable_id: u32,
+    unacked: Vec<Pending>,
+    next_delivery: u32,
+    out_of_order: HashMap<u32, Piece>,
+    // bytes in out_of_order
+    buffered: usize,
+    // what's been delivered so far of a reliable message in pieces
+    partial: Vec<u8>,
+    newest_unreliable: Option<u32>,
+    // the pieces so far of the newest unreliable message, by index
+    unreliable_pieces: Option<(u32, Vec<Option<Vec<u8>>>)>,
+    last_received: Instant,
+    // the most the other side has any business sending in one message
+    max_message_len: usize,
+    timeout_secs: f32,
+    cookie: u64,
+}
+
+struct Pending {
+    piece: Piece,
+    last_sent: Option<Instant>,
+    // packets this piece went out in
+    packets: Vec<u32>,
+}
+
+// the cookie goes first, so it can be checked without decoding the rest
+#[derive(Serialize, Deserialize)]
+struct Packet {
+    cookie: u64,
+    seq: u32,
+    ack: Option<u32>,
+    ack_bits: u32,
+    unreliable: Option<Piece>,
+    reliable: Vec<Piece>,
+}
+
+// a message, or part of one too big for a single packet
+#[derive(Clone, Serialize, Deserialize)]
+struct Piece {
+    // each reliable piece has its own, an unreliable message's pieces
+    // share the message's
+    id: u32,
+    // where it goes in its message, and how many pieces that has
+    index: u16,
+    count: u16,
+    data: Vec<u8>,
+}
+
+impl Link {
+    // messages longer than `max_message_len` are refused, and the link times
+    // out after `timeout_secs` without hearing anything
+    pub fn new(max_message_len: usize, timeout_secs: f32) -> Self {
+        Link {
+            seq: 0,
+            remote_seq: None,
+            ack_bits: 0,
+            next_message_id: 0,
+            next_unreliable_id: 0,
+            unacked: vec![],
+            next_delivery: 0,
+            out_of_order: HashMap::new(),
+            buffered: 0,
+            partial: vec![],
+            newest_unreliable: None,
+            unreliable_pieces: None,
+            last_received: Instant::now(),
+            max_message_len,
+            timeout_secs,
+            cookie: 0,
+        }
+    }
+
+    /// A packet with nothing in it but a cookie for the other side to send
+    /// back.
+    pub fn challenge(cookie: u64) -> Vec<u8> {
+        let packet = Packet {
+            cookie,
+            seq: 0,
+            ack: None,
+            ack_bits: 0,
+            unreliable: None,
+            reliable: vec![],
+        };
+
+        let mut datagram = wire::preamble().to_vec();
+        bc::serialize_into(&mut datagram, &packet, bc::Infinite).unwrap();
+        datagram
+    }
+
+    /// The cookie in `datagram`, if it's a packet at all.
+    pub fn cookie_of(datagram: &[u8]) -> Option<u64> {
+        if wire::check_preamble(datagram).is_err() {
+            return None;
+        }
+
+        wire::decode_bounded(&datagram[wire::PREAMBLE_LEN..], 8).ok()
+    }
+
+    pub fn cookie(&self) -> u64 {
+        self.cookie
+    }
+
+    pub fn queue_reliable(&mut self, data: Vec<u8>) {
+        let pieces = split(&data);
+        let count = pieces.len() as u16;
+
+        // each piece gets an id of its own, to be acked and delivered in
+        // order like a whole message
+        for (index, data) in pieces.into_iter().enumerate() {
+            let piece = Piece {
+                id: self.next_message_id,
+                index: index as u16,
+                count,
+                data,
+            };
+            self.next_message_id += 1;
+
+            self.unacked
+                .push(Pending {
+                          piece,
+                          last_sent: None,
+                          packets: vec![],
+                      });
+        }
+    }
+
+    /// The longest reliable message that's sure to get through. With a
+    /// longer one, more of it could be left waiting on a lost piece than the
+    /// other side keeps.
+    pub fn max_reliable_len() -> usize {
+        MAX_OUT_OF_ORDER_BYTES
+    }
+
+    // builds the datagrams to send now, holding the reliable pieces that are
+    // due and `unreliable`, in as many packets as it takes. There's always at
+    // least one, to carry the acks.
+    pub fn packets(&mut self, unreliable: Option<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut packets = vec![self.next_packet()];
+        let mut len = packet_len(&packets[0]);
+
+        let now = Instant::now();
+        for i in 0..self.unacked.len() {
+            let due = self.unacked[i]
+                .last_sent
+                .map_or(true, |t| t.elapsed().into_secs() >= RESEND_INTERVAL_SECS);
+            if !due {
+                continue;
+            }
+
+            let piece_len = bc::serialized_size(&self.unacked[i].piece) as usize;
+            if len + piece_len > MAX_PACKET_LEN {
+                packets.push(self.next_packet());
+                len = packet_len(packets.last().unwrap());
+            }
+
+            len += piece_len;
+            let packet = packets.last_mut().unwrap();
+            let m = &mut self.unacked[i];
+            m.last_sent = Some(now);
+            m.packets.push(packet.seq);
+            packet.reliable.push(m.piece.clone());
+        }
+
+        // an unreliable message starts in whatever room is left in the last
+        // packet, one piece to a packet
+        if let Some(data) = unreliable {
+            let id = self.next_unreliable_id;
+            self.next_unreliable_id += 1;
+
+            let pieces = split(&data);
+            let count = pieces.len() as u16;
+            for (index, data) in pieces.into_iter().enumerate() {
+                let piece = Piece {
+                    id,
+                    index: index as u16,
+                    count,
+                    data,
+                };
+
+                let piece_len = bc::serialized_size(&piece) as usize;
+                let full = packets.last().unwrap().unreliable.is_some() ||
+                           len + piece_len > MAX_PACKET_LEN;
+                if full {
+                    packets.push(self.next_packet());
+                    len = packet_len(packets.last().unwrap());
+                }
+
+                len += piece_len;
+                packets.last_mut().unwrap().unreliable = Some(piece);
+            }
+        }
+
+        packets
+            .iter()
+            .map(|packet| {
+                     let mut datagram = wire::preamble().to_vec();
+                     bc::serialize_into(&mut datagram, packet, bc::Infinite).unwrap();
+                     datagram
+                 })
+            .collect()
+    }
+
+    fn next_packet(&mut self) -> Packet {
+        let seq = self.seq;
+        self.seq += 1;
+
+        Packet {
+            cookie: self.cookie,
+            seq,
+            ack: self.remote_seq,
+            ack_bits: self.ack_bits,
+            unreliable: None,
+            reliable: vec![],
+        }
+    }
+
+    // returns the messages that can be handed to the game
+    pub fn receive(&mut self, datagram: &[u8]) -> bc::Result<Vec<Vec<u8>>> {
+        wire::check_preamble(datagram)?;
+        let packet: Packet = wire::decode_bounded(&datagram[wire::PREAMBLE_LEN..],
+                                                  MAX_DATAGRAM as u64)?;
+
+        // ignored without acking, so they're sent again once we've caught up
+        let next = self.next_delivery;
+        let too_far = |id: u32| id >= next && id - next >= MAX_OUT_OF_ORDER;
+        if packet.reliable.iter().any(|p| too_far(p.id)) {
+            let msg = "reliable message too far ahead";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg).into());
+        }
+
+        // no bigger and no more pieces than the longest message has
+        let max_count = self.max_message_len / MAX_PIECE_LEN + 1;
+        let bad = packet
+            .reliable
+            .iter()
+            .chain(packet.unreliable.iter())
+            .any(|p| {
+                     p.index >= p.count || p.count as usize > max_count ||
+                     p.data.len() > MAX_PIECE_LEN
+                 });
+        if bad {
+            let msg = "bad piece of a message";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg).into());
+        }
+
+        let max_len = self.max_message_len;
+        let too_long = packet
+            .reliable
+            .iter()
+            .chain(packet.unreliable.iter())
+            .any(|p| p.data.len() > max_len);
+        if too_long {
+            let msg = "message too long";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg).into());
+        }
+
+        if self.waiting_after(&packet.reliable) > MAX_OUT_OF_ORDER_BYTES {
+            let msg = "too many reliable messages waiting";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg).into());
+        }
+
+        self.last_received = Instant::now();
+
+        // a new cookie means the server threw away whatever came without
+        // one, so it all has to go again
+        if packet.cookie != 0 && packet.cookie != self.cookie {
+            self.cookie = packet.cookie;
+            for m in &mut self.unacked {
+                m.last_sent = None;
+            }
+        }
+
+        match self.remote_seq {
+            Some(remote) if packet.seq <= remote => {
+                let behind = remote - packet.seq;
+                if behind >= 1 && behind <= 32 {
+                    self.ack_bits |= 1 << (behind - 1);
+                }
+            }
+            Some(remote) => {
+                let ahead = packet.seq - remote;
+                self.ack_bits = if ahead > 32 {
+                    0
+                } else {
+                    // shifting a u32 by 32 overflows
+                    ((self.ack_bits as u64) << ahead) as u32 | 1 << (ahead - 1)
+                };
+                self.remote_seq = Some(packet.seq);
+            }
+            None => self.remote_seq = Some(packet.seq),
+        }
+
+        if let Some(ack) = packet.ack {
+            let bits = packet.ack_bits;
+            let acked = |seq: u32| {
+                seq == ack || (seq < ack && ack - seq <= 32 && bits & 1 << (ack - seq - 1) != 0)
+            };
+            self.unacked
+                .retain(|m| !m.packets.iter().any(|&seq| acked(seq)));
+        }
+
+        let mut messages = vec![];
+
+        if let Some(piece) = packet.unreliable {
+            if let Some(data) = self.take_unreliable(piece) {
+                messages.push(data);
+            }
+        }
+
+        for piece in packet.reliable {
+            if piece.id >= self.next_delivery && !self.out_of_order.contains_key(&piece.id) {
+                self.buffered += piece.data.len();
+                self.out_of_order.insert(piece.id, piece);
+            }
+        }
+
+        while let Some(piece) = self.out_of_order.remove(&self.next_delivery) {
+            self.buffered -= piece.data.len();
+            self.next_delivery += 1;
+
+            // only a peer that's up to no good sends one this long, there's
+            // no making sense of the rest of what they send after it
+            if self.partial.len() + piece.data.len() > max_len {
+                self.partial.clear();
+                let msg = "message too long";
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg).into());
+            }
+
+            self.partial.extend(piece.data);
+            if piece.index + 1 == piece.count {
+                messages.push(mem::replace(&mut self.partial, vec![]));
+            }
+        }
+
+        Ok(messages)
+    }
+
+    // the whole message once its last piece is in, if nothing newer has
+    // been delivered
+    fn take_unreliable(&mut self, piece: Piece) -> Option<Vec<u8>> {
+        if self.newest_unreliable.map_or(false, |newest| piece.id <= newest) {
+            return None;
+        }
+
+        // a newer message makes the one being put together useless
+        let newer = self.unreliable_pieces
+            .as_ref()
+            .map_or(true, |&(id, _)| piece.id > id);
+        if newer {
+            self.unreliable_pieces = Some((piece.id, vec![None; piece.count as usize]));
+        }
+
+        let done = {
+            let &mut (id, ref mut pieces) = self.unreliable_pieces.as_mut().unwrap();
+            if piece.id != id || piece.count as usize != pieces.len() {
+                return None;
+            }
+
+            pieces[piece.index as usize] = Some(piece.data);
+            pieces.iter().all(|p| p.is_some())
+        };
+
+        if !done {
+            return None;
+        }
+
+        let (id, pieces) = self.unreliable_pieces.take().unwrap();
+        self.newest_unreliable = Some(id);
+        Some(pieces.into_iter().flat_map(|p| p.unwrap()).collect())
+    }
+
+    // bytes of reliable pieces that would be left waiting for an earlier one
+    // after taking in `reliable`
+    fn waiting_after(&self, reliable: &[Piece]) -> usize {
+        let fresh: Vec<_> = reliable
+            .iter()
+            .filter(|p| p.id >= self.next_delivery && !self.out_of_order.contains_key(&p.id))
+            .collect();
+
+        let fresh_len: usize = fresh.iter().map(|p| p.data.len()).sum();
+        if self.buffered + fresh_len <= MAX_OUT_OF_ORDER_BYTES {
+            return self.buffered + fresh_len;
+        }
+
+        // the ones that can be delivered straight away don't count
+        let mut next = self.next_delivery;
+        while self.out_of_order.contains_key(&next) || fresh.iter().any(|p| p.id == next) {
+            next += 1;
+        }
+
+        let buffered: usize = self.out_of_order
+            .values()
+            .filter(|p| p.id >= next)
+            .map(|p| p.data.len())
+            .sum();
+        let arriving: usize = fresh
+            .iter()
+            .filter(|p| p.id >= next)
+            .map(|p| p.data.len())
+            .sum();
+        buffered + arriving
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.last_received.elapsed().into_secs() > self.timeout_secs
+    }
+
+    // whether any reliable messages are still waiting to be acked
+    pub fn has_unacked(&self) -> bool {
+        !self.unacked.is_empty()
+    }
+}
+
+// a message as the data of its pieces, there's always at least one
+fn split(data: &[u8]) -> Vec<Vec<u8>> {
+    if data.is_empty() {
+        return vec![vec![]];
+    }
+
+    data.chunks(MAX_PIECE_LEN).map(|c| c.to_vec()).collect()
+}
+
+fn packet_len(packet: &Packet) -> usize {
+    wire::PREAMBLE_LEN + bc::serialized_size(packet) as usize
+}
+
+/// One end of a UDP conversation. The link is shared, so that one thread
+/// can send while another receives.
+pub struct UdpPeer {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    link: Arc<Mutex<Link>>,
+    buf: Vec<u8>,
+}
+
+impl UdpPeer {
+    pub fn new(socket: UdpSocket, addr: SocketAddr, link: Arc<Mutex<Link>>) -> Self {
+        UdpPeer {
+            socket,
+            addr,
+            link,
+            buf: vec![0; MAX_DATAGRAM],
+        }
+    }
+
+    // there's no handshake at this level, this just picks a local port
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let addr = match addr.to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no address")),
+        };
+
+        let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local)?;
+        let link = Link::new(FromServerMsg::max_len() as usize, LINK_TIMEOUT_SECS);
+        Ok(UdpPeer::new(socket, addr, Arc::new(Mutex::new(link))))
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(UdpPeer::new(self.socket.try_clone()?, self.addr, self.link.clone()))
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.link.lock().unwrap().timed_out()
+    }
+
+    pub fn send(&mut self, data: &[u8], reliable: bool) -> io::Result<()> {
+        let packets = {
+            let mut link = self.link.lock().unwrap();
+            if link.timed_out() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "peer stopped responding"));
+            }
+
+            if reliable {
+                link.queue_reliable(data.to_vec());
+                link.packets(None)
+            } else {
+                link.packets(Some(data.to_vec()))
+            }
+        };
+
+        self.send_packets(packets)
+    }
+
+    // sends acks and any reliable messages that are due
+    pub fn flush(&mut self) -> io::Result<()> {
+        let packets = self.link.lock().unwrap().packets(None);
+        self.send_packets(packets)
+    }
+
+    fn send_packets(&self, packets: Vec<Vec<u8>>) -> io::Result<()> {
+        for packet in packets {
+            self.socket.send_to(&packet, self.addr)?;
+        }
+        Ok(())
+    }
+
+    // blocks until a packet from the peer arrives
+    pub fn recv(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        loop {
+            let (len, from) = self.socket.recv_from(&mut self.buf)?;
+            if from != self.addr {
+                continue;
+            }
+
+            // a server that speaks another version answers with just its preamble
+            wire::check_preamble(&self.buf[..len])?;
+
+            let mut link = self.link.lock().unwrap();
+            let cookie = link.cookie();
+            let msgs = link.receive(&self.buf[..len])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+            // the server wants to hear it all again along with the cookie
+            if link.cookie() != cookie {
+                for packet in link.packets(None) {
+                    self.socket.send_to(&packet, self.addr)?;
+                }
+            }
+
+            return Ok(msgs);
+        }
+    }
+}
+
+pub enum Conn {
+    Tcp(TcpStream),
+    Udp(UdpPeer),
+}
+
+impl Conn {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match *self {
+            Conn::Tcp(ref stream) => stream.try_clone().map(Conn::Tcp),
+            Conn::Udp(ref peer) => peer.try_clone().map(Conn::Udp),
+        }
+    }
+
+    // makes blocked reads and writes on every clone fail, a UDP socket is
+    // shared with other clients so it's left alone
+    pub fn shutdown(&self) -> io::Result<()> {
+        match *self {
+            Conn::Tcp(ref stream) => stream.shutdown(Shutdown::Both),
+            Conn::Udp(_) => Ok(()),
+        }
+    }
+
+    // only UDP needs to be asked, a dead TCP connection errors by itself
+    pub fn timed_out(&self) -> bool {
+        match *self {
+            Conn::Tcp(_) => false,
+            Conn::Udp(ref peer) => peer.timed_out(),
+        }
+    }
+
+    // `reliable` only matters for UDP, everything sent over TCP arrives
+    pub fn send<T: Serialize>(&mut self, msg: &T, reliable: bool) -> bc::Result<()> {
+        match *self {
+            Conn::Tcp(ref mut stream) => wire::write_frame(stream, msg),
+            Conn::Udp(ref mut peer) => {
+                let data = bc::serialize(msg, bc::Infinite)?;
+                Ok(peer.send(&data, reliable)?)
+            }
+        }
+    }
+
+    pub fn send_encoded(&mut self, data: &[u8], reliable: bool) -> io::Result<()> {
+        match *self {
+            Conn::Tcp(ref mut stream) => wire::write_frame_bytes(stream, data),
+            Conn::Udp(ref mut peer) => peer.send(data, reliable),
+        }
+    }
+
+    pub fn recv<T: Message>(&mut self) -> bc::Result<Vec<T>> {
+        match *self {
+            Conn::Tcp(ref mut stream) => Ok(vec![wire::read_frame(stream)?]),
+            Conn::Udp(ref mut peer) => {
+                let mut msgs = vec![];
+                for data in peer.recv()? {
+                    msgs.push(wire::decode(&data)?);
+                }
+                Ok(msgs)
+            }
+        }
+    }
+}
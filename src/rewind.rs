@@ -0,0 +1,43 @@
+use std::collections::{HashMap, VecDeque};
+
+use ludomath::vec2d::*;
+
+use {GameState, PlayerId};
+
+/// Where every player was over the last few ticks, so bullets can be checked
+/// against what a lagging shooter was looking at when they fired.
+pub struct PositionHistory {
+    ticks: VecDeque<(u64, HashMap<PlayerId, Vector>)>,
+    max_rewind: u32,
+}
+
+impl PositionHistory {
+    pub fn new(max_rewind: u32) -> Self {
+        PositionHistory {
+            ticks: VecDeque::new(),
+            max_rewind,
+        }
+    }
+
+    // how many ticks back bullets can be checked at
+    pub fn max_rewind(&self) -> u32 {
+        self.max_rewind
+    }
+
+    pub fn record(&mut self, gs: &GameState) {
+        let positions = gs.players.values().map(|p| (p.id, p.pos)).collect();
+        self.ticks.push_back((gs.tick, positions));
+
+        while self.ticks.len() > self.max_rewind as usize + 1 {
+            self.ticks.pop_front();
+        }
+    }
+
+    pub fn position(&self, tick: u64, id: PlayerId) -> Option<Vector> {
+        self.ticks
+            .iter()
+            .rev()
+            .find(|&&(t, _)| t == tick)
+            .and_then(|&(_, ref positions)| positions.get(&id).cloned())
+    }
+}
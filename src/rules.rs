@@ -0,0 +1,76 @@
+use std::io;
+
+/// What a game is played by. The server reads these from its config file
+/// and command line, and sends them to clients when they join, so their
+/// prediction goes by the same numbers.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    // the server simulates at this rate, and clients predict their own
+    // movement at the same rate so their steps line up
+    pub ticks_per_second: u32,
+    // how hard a movement key pushes
+    pub move_force: f32,
+    pub player_health: f32,
+    // for a bullet that hits head on, glancing hits do less
+    pub max_damage: f32,
+    // seconds dead before coming back
+    pub respawn_time: f32,
+    // tiles a second
+    pub bullet_speed: f32,
+    // more are turned away, players who lost their connection keep their
+    // place for as long as they can come back
+    pub max_players: u32,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            ticks_per_second: 120,
+            move_force: 40.0,
+            player_health: 100.0,
+            max_damage: 10.0,
+            respawn_time: 2.0,
+            bullet_speed: 10.0,
+            max_players: 64,
+        }
+    }
+}
+
+impl Rules {
+    // seconds a tick
+    pub fn dt(&self) -> f32 {
+        1.0 / self.ticks_per_second as f32
+    }
+
+    /// Turns down rules a game can't be played by, the client checks the
+    /// ones it's sent too.
+    pub fn check(&self) -> io::Result<()> {
+        let bad = |msg: &str| Err(io::Error::new(io::ErrorKind::InvalidData, msg.to_string()));
+
+        if self.ticks_per_second == 0 || self.ticks_per_second > 1000 {
+            return bad("ticks_per_second has to be between 1 and 1000");
+        }
+
+        let amounts = [("move_force", self.move_force),
+                       ("max_damage", self.max_damage),
+                       ("respawn_time", self.respawn_time),
+                       ("bullet_speed", self.bullet_speed)];
+
+        for &(name, v) in &amounts {
+            if !v.is_finite() || v < 0.0 {
+                return bad(&format!("{} can't be negative", name));
+            }
+        }
+
+        if !self.player_health.is_finite() || self.player_health <= 0.0 {
+            return bad("player_health has to be above 0");
+        }
+
+        if self.max_players == 0 {
+            return bad("max_players has to be at least 1");
+        }
+
+        Ok(())
+    }
+}
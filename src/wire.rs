@@ -0,0 +1,176 @@
+use std::io;
+use std::io::prelude::*;
+
+use bc;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use {FromServerMsg, ToServerMsg};
+
+// Both sides open a connection with a preamble of MAGIC and their
+// PROTOCOL_VERSION, and refuse to go on if the other side's doesn't match.
+// After that every message is a little endian u32 length followed by that
+// many bytes of bincode. UDP datagrams carry the preamble in front of every
+// packet instead, since there is no connection to open.
+//
+// Nothing read off the wire is decoded without a limit on its size, so a
+// bogus length in a frame or inside a message can't make us allocate more.
+
+pub const MAGIC: [u8; 4] = *b"RCAR";
+// bump this whenever the messages or their encoding change
+pub const PROTOCOL_VERSION: u32 = 12;
+pub const PREAMBLE_LEN: usize = 8;
+pub const MAX_FRAME_LEN: u32 = 8 * 1024 * 1024;
+// in characters
+pub const MAX_NAME_LEN: usize = 24;
+// a Login with the longest name is the biggest message a client sends
+pub const MAX_TO_SERVER_LEN: u32 = 256;
+
+/// A message that can be read off the wire, and the most bytes one can take.
+pub trait Message: Serialize + DeserializeOwned {
+    fn max_len() -> u32;
+}
+
+impl Message for ToServerMsg {
+    fn max_len() -> u32 {
+        MAX_TO_SERVER_LEN
+    }
+}
+
+impl Message for FromServerMsg {
+    // updates and the arena in Welcome can get big
+    fn max_len() -> u32 {
+        MAX_FRAME_LEN
+    }
+}
+
+pub fn preamble() -> [u8; PREAMBLE_LEN] {
+    let v = u32_to_bytes(PROTOCOL_VERSION);
+    [MAGIC[0], MAGIC[1], MAGIC[2], MAGIC[3], v[0], v[1], v[2], v[3]]
+}
+
+pub fn check_preamble(bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() < PREAMBLE_LEN || bytes[..4] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "peer isn't speaking the Recurse Arena protocol"));
+    }
+
+    let version = u32_from_bytes(&bytes[4..8]);
+    if version != PROTOCOL_VERSION {
+        let msg = format!("incompatible protocol version: peer speaks version {}, we speak \
+                           version {}",
+                          version,
+                          PROTOCOL_VERSION);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+
+    Ok(())
+}
+
+pub fn check_name(name: &str) -> io::Result<()> {
+    if name.trim().is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "name is empty"));
+    }
+
+    if name.chars().count() > MAX_NAME_LEN {
+        let msg = format!("name is longer than {} characters", MAX_NAME_LEN);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+
+    Ok(())
+}
+
+// sends our preamble and checks theirs
+pub fn handshake<S: Read + Write>(stream: &mut S) -> io::Result<()> {
+    stream.write_all(&preamble())?;
+
+    let mut theirs = [0; PREAMBLE_LEN];
+    stream.read_exact(&mut theirs)?;
+    check_preamble(&theirs)
+}
+
+pub fn write_frame<W: Write, T: Serialize>(w: &mut W, msg: &T) -> bc::Result<()> {
+    let data = bc::serialize(msg, bc::Infinite)?;
+    Ok(write_frame_bytes(w, &data)?)
+}
+
+// writes an already encoded message
+pub fn write_frame_bytes<W: Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+    // one write, so a frame can't end up split around another thread's
+    w.write_all(&encode_frame(data)?)
+}
+
+// the length prefixed frame for an already encoded message
+pub fn encode_frame(data: &[u8]) -> io::Result<Vec<u8>> {
+    if data.len() > MAX_FRAME_LEN as usize {
+        return Err(too_long(data.len(), MAX_FRAME_LEN));
+    }
+
+    let mut frame = Vec::with_capacity(4 + data.len());
+    frame.extend_from_slice(&u32_to_bytes(data.len() as u32));
+    frame.extend_from_slice(data);
+    Ok(frame)
+}
+
+pub fn read_frame<R: Read, T: Message>(r: &mut R) -> bc::Result<T> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+
+    let len = u32_from_bytes(&len);
+    if len > T::max_len() {
+        return Err(too_long(len as usize, T::max_len()).into());
+    }
+
+    let mut data = vec![0; len as usize];
+    r.read_exact(&mut data)?;
+    decode(&data)
+}
+
+// for reading without blocking: takes the first message off the front of
+// `buf` once all of it has arrived
+pub fn take_frame<T: Message>(buf: &mut Vec<u8>) -> bc::Result<Option<T>> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+
+    let len = u32_from_bytes(&buf[..4]);
+    if len > T::max_len() {
+        return Err(too_long(len as usize, T::max_len()).into());
+    }
+
+    let end = 4 + len as usize;
+    if buf.len() < end {
+        return Ok(None);
+    }
+
+    let msg = decode(&buf[4..end]);
+    buf.drain(..end);
+    msg.map(Some)
+}
+
+pub fn decode<T: Message>(data: &[u8]) -> bc::Result<T> {
+    if data.len() > T::max_len() as usize {
+        return Err(too_long(data.len(), T::max_len()).into());
+    }
+
+    decode_bounded(data, T::max_len() as u64)
+}
+
+// like bc::deserialize, but a length inside the data past `limit` is an
+// error instead of an allocation
+pub fn decode_bounded<T: DeserializeOwned>(mut data: &[u8], limit: u64) -> bc::Result<T> {
+    bc::deserialize_from(&mut data, bc::Bounded(limit))
+}
+
+fn too_long(len: usize, limit: u32) -> io::Error {
+    let msg = format!("message of {} bytes is over the limit of {}", len, limit);
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn u32_to_bytes(v: u32) -> [u8; 4] {
+    [v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]
+}
+
+fn u32_from_bytes(b: &[u8]) -> u32 {
+    b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
@@ -0,0 +1,95 @@
+// Map files, the ones that make an arena and the ones turned away.
+
+extern crate recurse_arena as ra;
+
+use ra::*;
+
+// the line and description of what's wrong with `map`
+fn parse_error(map: &str) -> (usize, String) {
+    match map.parse::<Arena>() {
+        Ok(_) => panic!("parsed a bad map:\n{}", map),
+        Err(ArenaError::Parse(line, msg)) => (line, msg),
+        Err(e) => panic!("not a parse error: {}", e),
+    }
+}
+
+#[test]
+fn parses() {
+    let arena: Arena = "\
+# a comment before the legend
+[legend]
+b = wall
+f = floor
+g=glow
+
+[map]
+bbbb
+bfgb
+bbbb
+"
+            .parse()
+            .unwrap();
+
+    assert_eq!(arena.width(), 4);
+    assert_eq!(arena.height(), 3);
+    assert_eq!(arena.tile(0, 0), Tile::Wall);
+    assert_eq!(arena.tile(1, 1), Tile::Floor);
+    assert_eq!(arena.tile(2, 1), Tile::Glow);
+    assert_eq!(arena.spawn_points().len(), 1);
+}
+
+#[test]
+fn built_in_map_parses() {
+    let arena = Arena::logo();
+    assert!(arena.width() > 0 && arena.height() > 0);
+    assert!(!arena.spawn_points().is_empty());
+}
+
+#[test]
+fn bad_legend() {
+    let (line, msg) = parse_error("[legend]\nb wall\n[map]\nb\n");
+    assert_eq!(line, 2);
+    assert!(msg.contains("expected"), "{}", msg);
+
+    let (line, msg) = parse_error("[legend]\nbb = wall\n[map]\nbb\n");
+    assert_eq!(line, 2);
+    assert!(msg.contains("single character"), "{}", msg);
+
+    let (line, msg) = parse_error("[legend]\nb = lava\n[map]\nb\n");
+    assert_eq!(line, 2);
+    assert!(msg.contains("unknown tile kind 'lava'"), "{}", msg);
+}
+
+#[test]
+fn ragged_rows() {
+    let (line, msg) = parse_error("[legend]\nb = wall\nf = floor\n[map]\nbbbb\nbff\nbbbb\n");
+    assert_eq!(line, 6);
+    assert!(msg.contains("3 tiles wide, expected 4"), "{}", msg);
+}
+
+#[test]
+fn unknown_character() {
+    let (line, msg) = parse_error("[legend]\nb = wall\nf = floor\n[map]\nbbb\nbxb\nbbb\n");
+    assert_eq!(line, 6);
+    assert!(msg.contains("'x' is not in the legend"), "{}", msg);
+}
+
+#[test]
+fn no_floor() {
+    let (_, msg) = parse_error("[legend]\nb = wall\nv = void\n[map]\nbbb\nbvb\nbbb\n");
+    assert!(msg.contains("no floor tiles"), "{}", msg);
+
+    let (_, msg) = parse_error("[legend]\nb = wall\n[map]\n\n");
+    assert!(msg.contains("empty"), "{}", msg);
+}
+
+#[test]
+fn map_before_legend() {
+    let (line, msg) = parse_error("[map]\nbbb\n[legend]\nb = wall\n");
+    assert_eq!(line, 1);
+    assert!(msg.contains("must come after"), "{}", msg);
+
+    let (line, msg) = parse_error("bbb\n[legend]\nb = wall\n");
+    assert_eq!(line, 1);
+    assert!(msg.contains("expected [legend]"), "{}", msg);
+}
@@ -0,0 +1,158 @@
+// Circles against squares and other circles, the shapes players, bullets
+// and walls collide as.
+
+extern crate ludomath;
+extern crate recurse_arena as ra;
+
+use ludomath::vec2d::*;
+
+use ra::*;
+
+fn assert_near(a: Vector, b: Vector) {
+    assert!((a - b).magnitude() < 0.001, "{:?} isn't {:?}", a, b);
+}
+
+fn unit_square() -> CSquare {
+    CSquare::new(Vector::new(0.0, 0.0), 1.0)
+}
+
+#[test]
+fn contact_square_apart() {
+    let s = unit_square();
+    assert!(CCircle::new(Vector::new(2.0, 0.5), 0.5)
+                .contact_square(&s)
+                .is_none());
+    // just touching isn't overlapping
+    assert!(CCircle::new(Vector::new(1.5, 0.5), 0.5)
+                .contact_square(&s)
+                .is_none());
+}
+
+#[test]
+fn contact_square_side() {
+    let c = CCircle::new(Vector::new(1.3, 0.5), 0.5)
+        .contact_square(&unit_square())
+        .unwrap();
+    assert_near(c.normal, Vector::new(1.0, 0.0));
+    assert!((c.depth - 0.2).abs() < 0.001, "depth {}", c.depth);
+
+    let c = CCircle::new(Vector::new(0.5, -0.4), 0.5)
+        .contact_square(&unit_square())
+        .unwrap();
+    assert_near(c.normal, Vector::new(0.0, -1.0));
+    assert!((c.depth - 0.1).abs() < 0.001, "depth {}", c.depth);
+}
+
+#[test]
+fn contact_square_corner() {
+    let c = CCircle::new(Vector::new(1.2, 1.2), 0.5)
+        .contact_square(&unit_square())
+        .unwrap();
+    let diagonal = 0.5f32.sqrt();
+    assert_near(c.normal, Vector::new(diagonal, diagonal));
+    assert!((c.depth - (0.5 - 0.08f32.sqrt())).abs() < 0.001,
+            "depth {}",
+            c.depth);
+}
+
+#[test]
+fn contact_square_center_inside() {
+    // pushed out the nearest side, deep enough to clear it
+    let c = CCircle::new(Vector::new(0.9, 0.5), 0.5)
+        .contact_square(&unit_square())
+        .unwrap();
+    assert_near(c.normal, Vector::new(1.0, 0.0));
+    assert!((c.depth - 0.6).abs() < 0.001, "depth {}", c.depth);
+
+    let c = CCircle::new(Vector::new(0.5, 0.8), 0.1)
+        .contact_square(&unit_square())
+        .unwrap();
+    assert_near(c.normal, Vector::new(0.0, 1.0));
+    assert!((c.depth - 0.3).abs() < 0.001, "depth {}", c.depth);
+}
+
+#[test]
+fn sweep_circle_hit() {
+    let bullet = CCircle::new(Vector::new(0.0, 0.0), 0.1);
+    let player = CCircle::new(Vector::new(5.0, 0.0), 0.5);
+    let hit = bullet.sweep_circle(Vector::new(10.0, 0.0), player).unwrap();
+    assert!((hit.time - 0.44).abs() < 0.001, "time {}", hit.time);
+    assert_near(hit.point, Vector::new(4.5, 0.0));
+    assert_near(hit.normal, Vector::new(-1.0, 0.0));
+}
+
+#[test]
+fn sweep_circle_miss() {
+    let bullet = CCircle::new(Vector::new(0.0, 0.0), 0.1);
+    let motion = Vector::new(10.0, 0.0);
+    // passes by
+    assert!(bullet.sweep_circle(motion, CCircle::new(Vector::new(5.0, 2.0), 0.5))
+                  .is_none());
+    // moving away
+    assert!(bullet.sweep_circle(motion, CCircle::new(Vector::new(-5.0, 0.0), 0.5))
+                  .is_none());
+    // stops short
+    assert!(bullet.sweep_circle(Vector::new(1.0, 0.0),
+                                CCircle::new(Vector::new(5.0, 0.0), 0.5))
+                  .is_none());
+}
+
+#[test]
+fn sweep_circle_already_touching() {
+    let bullet = CCircle::new(Vector::new(0.0, 0.0), 0.5);
+    let hit = bullet.sweep_circle(Vector::new(1.0, 0.0),
+                                  CCircle::new(Vector::new(0.8, 0.0), 0.5))
+                    .unwrap();
+    assert_eq!(hit.time, 0.0);
+    assert_near(hit.normal, Vector::new(-1.0, 0.0));
+
+    // right on top of each other, still some way out
+    let hit = bullet.sweep_circle(Vector::new(0.0, 0.0), bullet).unwrap();
+    assert_eq!(hit.time, 0.0);
+    assert!((hit.normal.magnitude() - 1.0).abs() < 0.001);
+}
+
+#[test]
+fn sweep_square_does_not_tunnel() {
+    // far enough in one tick to jump clean over the wall
+    let bullet = CCircle::new(Vector::new(0.0, 0.5), 0.05);
+    let wall = CSquare::new(Vector::new(3.0, 0.0), 1.0);
+    let hit = bullet.sweep_square(Vector::new(10.0, 0.0), &wall).unwrap();
+    assert!((hit.time - 0.295).abs() < 0.001, "time {}", hit.time);
+    assert_near(hit.point, Vector::new(3.0, 0.5));
+    assert_near(hit.normal, Vector::new(-1.0, 0.0));
+}
+
+#[test]
+fn sweep_square_miss() {
+    let wall = CSquare::new(Vector::new(3.0, 0.0), 1.0);
+    let bullet = CCircle::new(Vector::new(0.0, -1.0), 0.05);
+    assert!(bullet.sweep_square(Vector::new(10.0, 0.0), &wall).is_none());
+    let bullet = CCircle::new(Vector::new(0.0, 0.5), 0.05);
+    assert!(bullet.sweep_square(Vector::new(-10.0, 0.0), &wall).is_none());
+}
+
+#[test]
+fn sweep_square_already_touching() {
+    let wall = CSquare::new(Vector::new(3.0, 0.0), 1.0);
+    let bullet = CCircle::new(Vector::new(2.95, 0.5), 0.1);
+    let hit = bullet.sweep_square(Vector::new(1.0, 0.0), &wall).unwrap();
+    assert_eq!(hit.time, 0.0);
+}
+
+#[test]
+fn sweep_square_corner() {
+    let wall = CSquare::new(Vector::new(3.0, 0.0), 1.0);
+
+    // clips the rounded corner
+    let bullet = CCircle::new(Vector::new(2.0, -0.095), 0.1);
+    let hit = bullet.sweep_square(Vector::new(2.0, 0.0), &wall).unwrap();
+    let x = 3.0 - 0.000975f32.sqrt();
+    assert!((hit.time - (x - 2.0) / 2.0).abs() < 0.001, "time {}", hit.time);
+    assert_near(hit.point, Vector::new(3.0, 0.0));
+    assert_near(hit.normal, Vector::new((x - 3.0) / 0.1, -0.95));
+
+    // inside the square grown by the radius, but around the corner
+    let bullet = CCircle::new(Vector::new(2.0, 0.85), 0.1);
+    assert!(bullet.sweep_square(Vector::new(2.0, -2.0), &wall).is_none());
+}
@@ -0,0 +1,387 @@
+// A server running in the test's own process on a loopback port, and
+// scripted clients talking to it over the real protocol.
+//
+// The server only ticks when the test says so, on a clock that moves one
+// tick's worth each time. After every tick the harness waits until every
+// client has seen it, so what clients have received is always up to date
+// with the server's game state.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bc;
+use ra::*;
+
+// how long to wait on the network before calling a test failed
+const TIMEOUT_SECS: u64 = 5;
+
+// a corridor six tiles long, so two players spawn at either end of it and
+// can always see each other
+pub const CORRIDOR: &str = "\
+[legend]
+b = wall
+f = floor
+[map]
+bbbbbbbb
+bffffffb
+bbbbbbbb
+";
+
+// an open room with space to walk around in
+pub const ROOM: &str = "\
+[legend]
+b = wall
+f = floor
+[map]
+bbbbbbbb
+bffffffb
+bffffffb
+bffffffb
+bffffffb
+bbbbbbbb
+";
+
+pub struct Harness {
+    pub server: Server<ManualClock>,
+    clock: ManualClock,
+    addr: SocketAddr,
+    transport: Transport,
+    clients: HashMap<PlayerId, TestClient>,
+}
+
+struct TestClient {
+    // for sending, the reader thread has its own handle
+    conn: Conn,
+    received: Receiver<Vec<Received>>,
+    seq: u32,
+    // every event received so far, in order
+    events: Vec<Event>,
+    // the newest state received
+    state: Option<GameState>,
+    disconnect: Option<String>,
+}
+
+enum Received {
+    Events(Vec<Event>),
+    State(GameState),
+    Disconnect(String),
+}
+
+impl Harness {
+    pub fn new(map: &str, transport: Transport) -> Self {
+        Harness::with_config(ServerConfig::new(map.parse().unwrap()), transport)
+    }
+
+    pub fn with_config(config: ServerConfig, transport: Transport) -> Self {
+        let clock = ManualClock::new();
+        let mut server = Server::with_clock(config, clock.clone());
+        let addr = server
+            .bind_to(&"127.0.0.1:0".parse().unwrap(), transport)
+            .unwrap();
+
+        Harness {
+            server,
+            clock,
+            addr,
+            transport,
+            clients: HashMap::new(),
+        }
+    }
+
+    pub fn game_state(&self) -> &GameState {
+        self.server.game_state()
+    }
+
+    pub fn player(&self, id: PlayerId) -> &Player {
+        &self.game_state().players[&id]
+    }
+
+    pub fn rules(&self) -> &Rules {
+        &self.server.config().rules
+    }
+
+    // logs a client in and ticks until their player is in the game
+    pub fn join(&mut self, name: &str) -> PlayerId {
+        match self.try_join(name) {
+            Ok(id) => id,
+            Err(reason) => panic!("turned away: {}", reason),
+        }
+    }
+
+    // like join, but the reason the server gave for turning them away is
+    // returned instead
+    pub fn try_join(&mut self, name: &str) -> Result<PlayerId, String> {
+        let mut conn = match self.transport {
+            Transport::Tcp => {
+                let mut stream = TcpStream::connect(self.addr).unwrap();
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))
+                    .unwrap();
+                handshake(&mut stream).unwrap();
+                Conn::Tcp(stream)
+            }
+            Transport::Udp => {
+                let peer = UdpPeer::connect(self.addr).unwrap();
+                peer.set_read_timeout(Some(Duration::from_millis(10)))
+                    .unwrap();
+                Conn::Udp(peer)
+            }
+        };
+
+        conn.send(&ToServerMsg::Login(name.to_string(), None), true)
+            .unwrap();
+
+        let id = welcome(&mut conn)?;
+
+        let (sender, received) = channel();
+        let reader = conn.try_clone().unwrap();
+        thread::spawn(move || listen(reader, sender));
+
+        self.clients
+            .insert(id,
+                    TestClient {
+                        conn,
+                        received,
+                        seq: 0,
+                        events: vec![],
+                        state: None,
+                        disconnect: None,
+                    });
+
+        self.tick_until(10, |gs| gs.players.contains_key(&id));
+        Ok(id)
+    }
+
+    // logs out and waits for the server to let them go
+    pub fn leave(&mut self, id: PlayerId) {
+        let mut client = self.clients.remove(&id).unwrap();
+        client
+            .conn
+            .send(&ToServerMsg::Logout, true)
+            .unwrap();
+        self.tick_until(10, |gs| !gs.players.contains_key(&id));
+    }
+
+    // closes the connection without a word, like a crashed client
+    pub fn drop_connection(&mut self, id: PlayerId) {
+        let client = self.clients.remove(&id).unwrap();
+        client.conn.shutdown().unwrap();
+    }
+
+    pub fn tick(&mut self) {
+        let ticks_per_second = self.rules().ticks_per_second;
+        self.clock
+            .advance(Duration::from_secs(1) / ticks_per_second);
+        self.server.tick();
+
+        let tick = self.game_state().tick;
+        for client in self.clients.values_mut() {
+            client.sync(tick);
+        }
+    }
+
+    pub fn ticks(&mut self, n: u32) {
+        for _ in 0..n {
+            self.tick();
+        }
+    }
+
+    // ticks until `done` says so, panicking after `max` ticks
+    pub fn tick_until<F: Fn(&GameState) -> bool>(&mut self, max: u32, done: F) {
+        for _ in 0..max {
+            if done(self.game_state()) {
+                return;
+            }
+            self.tick();
+        }
+
+        assert!(done(self.game_state()),
+                "still waiting after {} ticks",
+                max);
+    }
+
+    // sends any message at all, without waiting on the server
+    pub fn send(&mut self, id: PlayerId, msg: &ToServerMsg) {
+        self.clients
+            .get_mut(&id)
+            .unwrap()
+            .conn
+            .send(msg, true)
+            .unwrap();
+    }
+
+    // sends an input, and ticks until the server has handled it
+    pub fn input(&mut self, id: PlayerId, input: Input) {
+        let seq = {
+            let client = self.clients.get_mut(&id).unwrap();
+            client.seq += 1;
+            client
+                .conn
+                .send(&ToServerMsg::Input(client.seq, input), true)
+                .unwrap();
+            client.seq
+        };
+
+        self.tick_until(100, |gs| gs.players[&id].last_input >= seq);
+    }
+
+    pub fn press(&mut self, id: PlayerId, b: Button) {
+        let dir = self.player(id).dir;
+        let tick = self.seen_tick(id);
+        self.input(id, Input::Press(b, dir, tick));
+    }
+
+    pub fn release(&mut self, id: PlayerId, b: Button) {
+        self.input(id, Input::Release(b));
+    }
+
+    pub fn aim_at(&mut self, id: PlayerId, target: PlayerId) {
+        let dir = (self.player(target).pos - self.player(id).pos).normalize();
+        self.input(id, Input::DirChanged(dir));
+    }
+
+    // one click, then waits for the bullet to land and the gun to cool down
+    pub fn shoot(&mut self, id: PlayerId) {
+        let ticks_per_second = self.rules().ticks_per_second;
+        self.press(id, Button::LeftMouse);
+        self.release(id, Button::LeftMouse);
+        self.tick_until(ticks_per_second, |gs| gs.bullets.is_empty());
+        self.ticks(ticks_per_second / 8);
+    }
+
+    pub fn events(&self, id: PlayerId) -> &[Event] {
+        &self.clients[&id].events
+    }
+
+    // the state as the client sees it
+    pub fn client_state(&self, id: PlayerId) -> &GameState {
+        self.clients[&id].state.as_ref().unwrap()
+    }
+
+    pub fn disconnect_reason(&self, id: PlayerId) -> Option<&str> {
+        self.clients[&id].disconnect.as_ref().map(|s| &s[..])
+    }
+
+    fn seen_tick(&self, id: PlayerId) -> u64 {
+        self.clients[&id].state.as_ref().map_or(0, |gs| gs.tick)
+    }
+}
+
+impl TestClient {
+    // takes in everything up to and including `tick`
+    fn sync(&mut self, tick: u64) {
+        let deadline = Instant::now() + Duration::from_secs(TIMEOUT_SECS);
+
+        while self.disconnect.is_none() &&
+              self.state.as_ref().map_or(true, |gs| gs.tick < tick) {
+            let now = Instant::now();
+            if now >= deadline {
+                panic!("client never saw tick {}", tick);
+            }
+
+            // a whole batch at a time, UDP puts a tick's events after its
+            // update in the same packet
+            let batch = match self.received.recv_timeout(deadline - now) {
+                Ok(batch) => batch,
+                Err(RecvTimeoutError::Timeout) => panic!("client never saw tick {}", tick),
+                Err(RecvTimeoutError::Disconnected) => panic!("client lost its connection"),
+            };
+
+            for received in batch {
+                match received {
+                    Received::Events(events) => self.events.extend(events),
+                    Received::State(gs) => self.state = Some(gs),
+                    Received::Disconnect(reason) => self.disconnect = Some(reason),
+                }
+            }
+        }
+    }
+}
+
+fn welcome(conn: &mut Conn) -> Result<PlayerId, String> {
+    let deadline = Instant::now() + Duration::from_secs(TIMEOUT_SECS);
+
+    while Instant::now() < deadline {
+        let msgs = match conn.recv() {
+            Ok(msgs) => msgs,
+            // the server only learns about a UDP client from its packets
+            Err(_) => {
+                if let Conn::Udp(ref mut peer) = *conn {
+                    peer.flush().unwrap();
+                    continue;
+                }
+                panic!("no welcome");
+            }
+        };
+
+        for msg in msgs {
+            match msg {
+                FromServerMsg::Welcome(id, _, _, _) => return Ok(id),
+                FromServerMsg::Disconnect { reason } => return Err(reason),
+                _ => {}
+            }
+        }
+    }
+
+    panic!("no welcome");
+}
+
+// acks updates and answers pings like the real client, and passes on what
+// arrives
+fn listen(mut conn: Conn, sender: Sender<Vec<Received>>) {
+    let mut history: VecDeque<GameState> = VecDeque::new();
+
+    loop {
+        let msgs = match conn.recv::<FromServerMsg>() {
+            Ok(msgs) => msgs,
+            Err(e) => {
+                if let bc::ErrorKind::IoError(ref e) = *e {
+                    if let Conn::Udp(ref mut peer) = conn {
+                        // keep the acks flowing while nothing comes in
+                        if e.kind() == ::std::io::ErrorKind::WouldBlock ||
+                           e.kind() == ::std::io::ErrorKind::TimedOut {
+                            let _ = peer.flush();
+                            continue;
+                        }
+                    }
+                }
+                return;
+            }
+        };
+
+        let mut batch = vec![];
+        for msg in msgs {
+            batch.push(match msg {
+                FromServerMsg::Update(delta) => {
+                    let baseline = delta
+                        .baseline
+                        .map(|tick| history.iter().find(|gs| gs.tick == tick).unwrap());
+                    let gs = delta.apply(baseline);
+
+                    let _ = conn.send(&ToServerMsg::Ack(gs.tick), false);
+
+                    history.push_back(gs.clone());
+                    if history.len() > SNAPSHOT_HISTORY {
+                        history.pop_front();
+                    }
+
+                    Received::State(gs)
+                }
+                FromServerMsg::Events(events) => Received::Events(events),
+                FromServerMsg::Ping(n) => {
+                    let _ = conn.send(&ToServerMsg::Pong(n), false);
+                    continue;
+                }
+                FromServerMsg::Disconnect { reason } => Received::Disconnect(reason),
+                FromServerMsg::Welcome(..) => continue,
+            });
+        }
+
+        if sender.send(batch).is_err() {
+            return;
+        }
+    }
+}
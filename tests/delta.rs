@@ -0,0 +1,141 @@
+// Snapshots sent as deltas against an older state, and rebuilt from them.
+
+extern crate ludomath;
+extern crate recurse_arena as ra;
+
+use std::collections::HashMap;
+
+use ludomath::vec2d::*;
+
+use ra::*;
+
+fn player(id: u32, x: f32) -> Player {
+    Player {
+        id: PlayerId(id),
+        name: format!("player {}", id),
+        dir: Vector::new(1.0, 0.0),
+        pos: Vector::new(x, 2.0),
+        health: 100.0,
+        ..Player::default()
+    }
+}
+
+fn bullet(id: u32, x: f32) -> Bullet {
+    Bullet {
+        pos: Vector::new(x, 3.0),
+        vel: Vector::new(10.0, 0.0),
+        pid: PlayerId(1),
+        id: BulletId(id),
+        rewind: 0,
+    }
+}
+
+fn state(tick: u64, players: Vec<Player>, bullets: Vec<Bullet>) -> GameState {
+    GameState {
+        players: players.into_iter().map(|p| (p.id, p)).collect(),
+        bullets,
+        events: vec![],
+        tick,
+    }
+}
+
+fn assert_same(a: &GameState, b: &GameState) {
+    assert_eq!(a.tick, b.tick);
+
+    let mut ids: Vec<_> = a.players.keys().collect();
+    let mut other_ids: Vec<_> = b.players.keys().collect();
+    ids.sort();
+    other_ids.sort();
+    assert_eq!(ids, other_ids);
+
+    for (id, p) in &a.players {
+        let q = &b.players[id];
+        assert_eq!(p.id, q.id);
+        assert_eq!(p.name, q.name);
+        assert_eq!(p.dir, q.dir);
+        assert_eq!(p.pos, q.pos);
+        assert_eq!(p.vel, q.vel);
+        assert_eq!(p.force, q.force);
+        assert_eq!(p.health, q.health);
+        assert_eq!(p.respawn_timer, q.respawn_timer);
+        assert_eq!(p.score, q.score);
+        assert_eq!(p.last_input, q.last_input);
+        assert_eq!(p.last_input_tick, q.last_input_tick);
+        assert_eq!(p.rtt, q.rtt);
+    }
+
+    // the order bullets end up in doesn't matter
+    assert_eq!(a.bullets.len(), b.bullets.len());
+    let bullets: HashMap<_, _> = b.bullets.iter().map(|b| (b.id, b)).collect();
+    for x in &a.bullets {
+        let y = bullets[&x.id];
+        assert_eq!(x.pos, y.pos);
+        assert_eq!(x.vel, y.vel);
+        assert_eq!(x.pid, y.pid);
+        assert_eq!(x.rewind, y.rewind);
+    }
+}
+
+#[test]
+fn without_baseline() {
+    let current = state(7, vec![player(1, 1.0), player(2, 5.0)], vec![bullet(1, 2.0)]);
+    let delta = Delta::new(None, &current);
+    assert_eq!(delta.baseline, None);
+    assert_eq!(delta.players.len(), 2);
+    assert_eq!(delta.bullets_spawned.len(), 1);
+
+    assert_same(&delta.apply(None), &current);
+}
+
+#[test]
+fn against_baseline() {
+    let old = state(10,
+                    vec![player(1, 1.0), player(2, 5.0), player(3, 9.0)],
+                    vec![bullet(1, 2.0), bullet(2, 4.0), bullet(3, 6.0)]);
+
+    let mut moved = player(1, 1.5);
+    moved.score = 3;
+    moved.last_input = 12;
+    moved.last_input_tick = 11;
+    let current = state(12,
+                        vec![moved, player(2, 5.0), player(4, 0.0)],
+                        vec![bullet(1, 2.0), bullet(2, 4.5), bullet(4, 8.0)]);
+
+    let delta = Delta::new(Some(&old), &current);
+    assert_eq!(delta.baseline, Some(10));
+    // only what changed goes out
+    let changed: Vec<_> = delta.players.iter().map(|&(id, _)| id).collect();
+    assert!(!changed.contains(&PlayerId(2)));
+    assert!(changed.contains(&PlayerId(1)) && changed.contains(&PlayerId(4)));
+    assert_eq!(delta.players_removed, vec![PlayerId(3)]);
+    assert_eq!(delta.bullets_spawned.len(), 1);
+    assert_eq!(delta.bullets_moved.len(), 1);
+    assert_eq!(delta.bullets_removed, vec![BulletId(3)]);
+
+    assert_same(&delta.apply(Some(&old)), &current);
+}
+
+#[test]
+fn nothing_changed() {
+    let old = state(3, vec![player(1, 1.0)], vec![bullet(1, 2.0)]);
+    let mut current = old.clone();
+    current.tick = 4;
+
+    let delta = Delta::new(Some(&old), &current);
+    assert!(delta.players.is_empty());
+    assert!(delta.players_removed.is_empty());
+    assert!(delta.bullets_spawned.is_empty());
+    assert!(delta.bullets_moved.is_empty());
+    assert!(delta.bullets_removed.is_empty());
+
+    assert_same(&delta.apply(Some(&old)), &current);
+}
+
+#[test]
+#[should_panic]
+fn wrong_baseline() {
+    let old = state(3, vec![player(1, 1.0)], vec![]);
+    let current = state(4, vec![player(1, 2.0)], vec![]);
+    let delta = Delta::new(Some(&old), &current);
+    delta.apply(Some(&current));
+}
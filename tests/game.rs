@@ -0,0 +1,308 @@
+// Whole games played against a real server, by clients that speak the same
+// protocol the game client does. Each one is played over both transports.
+
+extern crate bincode as bc;
+extern crate ludomath;
+extern crate recurse_arena as ra;
+
+mod common;
+
+use std::f32;
+
+use ludomath::vec2d::*;
+
+use common::*;
+use ra::*;
+
+fn join_and_leave(transport: Transport) {
+    let mut h = Harness::new(CORRIDOR, transport);
+
+    let alice = h.join("alice");
+    let bob = h.join("bob");
+
+    assert_eq!(h.game_state().players.len(), 2);
+    assert_eq!(h.player(alice).name, "alice");
+    assert_eq!(h.player(bob).name, "bob");
+    assert_eq!(h.client_state(alice).players.len(), 2);
+
+    // alice was there to see bob come in
+    assert!(h.events(alice)
+                .iter()
+                .any(|e| match *e {
+                         Event::PlayerJoined(id) => id == bob,
+                         _ => false,
+                     }));
+
+    h.leave(bob);
+    h.tick();
+
+    assert_eq!(h.game_state().players.len(), 1);
+    assert!(!h.client_state(alice).players.contains_key(&bob));
+    assert!(h.events(alice)
+                .iter()
+                .any(|e| match *e {
+                         Event::PlayerLeft(id, LeaveReason::Quit) => id == bob,
+                         _ => false,
+                     }));
+}
+
+#[test]
+fn join_and_leave_tcp() {
+    join_and_leave(Transport::Tcp);
+}
+
+#[test]
+fn join_and_leave_udp() {
+    join_and_leave(Transport::Udp);
+}
+
+fn movement(transport: Transport) {
+    let mut h = Harness::new(ROOM, transport);
+    let alice = h.join("alice");
+
+    // there's room to walk in one direction or the other from any tile
+    let start = h.player(alice).pos;
+    let (button, sign) = if start.x < 4.0 {
+        (Button::D, 1.0)
+    } else {
+        (Button::A, -1.0)
+    };
+
+    let ticks_per_second = h.rules().ticks_per_second;
+    h.press(alice, button);
+    h.ticks(ticks_per_second / 4);
+    h.release(alice, button);
+
+    let moved = h.player(alice).pos - start;
+    assert!(moved.x * sign > 0.5, "only moved {:?}", moved);
+    assert!(moved.y.abs() < 0.01, "drifted {:?}", moved);
+
+    // and slows to a stop once let go
+    h.ticks(ticks_per_second);
+    let stopped = h.player(alice).pos;
+    assert!(h.player(alice).vel.magnitude() < 0.01);
+
+    // the client sees it where the server has it
+    let seen = h.client_state(alice).players[&alice].pos;
+    assert!((seen - stopped).magnitude() < 0.001);
+}
+
+#[test]
+fn movement_tcp() {
+    movement(Transport::Tcp);
+}
+
+#[test]
+fn movement_udp() {
+    movement(Transport::Udp);
+}
+
+fn shoot_kill_respawn(transport: Transport) {
+    let mut h = Harness::new(CORRIDOR, transport);
+
+    let alice = h.join("alice");
+    let bob = h.join("bob");
+    h.aim_at(alice, bob);
+
+    let mut shots = 0;
+    while h.player(bob).health > 0.0 {
+        assert!(shots < 20, "bob still alive after {} shots", shots);
+        h.shoot(alice);
+        shots += 1;
+    }
+
+    let hits = h.events(bob)
+        .iter()
+        .filter(|e| match **e {
+                    Event::BulletHitPlayer(ref b, id, _, _) => b.pid == alice && id == bob,
+                    _ => false,
+                })
+        .count();
+    let fired = h.events(bob)
+        .iter()
+        .filter(|e| match **e {
+                    Event::BulletFired(_) => true,
+                    _ => false,
+                })
+        .count();
+
+    assert_eq!(fired, shots);
+    assert_eq!(hits, shots);
+
+    // the last one did it, and alice got the point for it
+    h.tick();
+    for &id in &[alice, bob] {
+        assert!(h.events(id)
+                    .iter()
+                    .any(|e| match *e {
+                             Event::PlayerDied(died, killer) => died == bob && killer == alice,
+                             _ => false,
+                         }));
+    }
+
+    assert_eq!(h.player(alice).score, 1);
+    assert_eq!(h.player(bob).score, 0);
+    assert_eq!(h.client_state(bob).players[&alice].score, 1);
+
+    // back on their feet once the timer runs out
+    let health = h.rules().player_health;
+    let respawn_ticks = (h.rules().respawn_time * h.rules().ticks_per_second as f32) as u32;
+    h.tick_until(respawn_ticks + 10, |gs| gs.players[&bob].health == health);
+    h.tick();
+
+    assert!(h.events(alice)
+                .iter()
+                .any(|e| match *e {
+                         Event::PlayerRespawned(id) => id == bob,
+                         _ => false,
+                     }));
+    assert_eq!(h.client_state(alice).players[&bob].health, health);
+    assert_eq!(h.player(alice).score, 1);
+}
+
+#[test]
+fn shoot_kill_respawn_tcp() {
+    shoot_kill_respawn(Transport::Tcp);
+}
+
+#[test]
+fn shoot_kill_respawn_udp() {
+    shoot_kill_respawn(Transport::Udp);
+}
+
+#[test]
+fn dropped_connection_times_out() {
+    // UDP never hears of a connection closing, so it has to time out
+    let mut h = Harness::new(CORRIDOR, Transport::Udp);
+
+    let alice = h.join("alice");
+    let bob = h.join("bob");
+    h.drop_connection(bob);
+
+    let timeout = h.server.config().heartbeat_timeout;
+    let timeout_ticks = (timeout * h.rules().ticks_per_second as f32) as u32;
+    h.tick_until(timeout_ticks * 2, |gs| !gs.players.contains_key(&bob));
+    h.tick();
+
+    assert!(h.events(alice)
+                .iter()
+                .any(|e| match *e {
+                         Event::PlayerLeft(id, LeaveReason::TimedOut) => id == bob,
+                         _ => false,
+                     }));
+}
+
+fn logging_in_twice_gets_kicked(transport: Transport) {
+    let mut h = Harness::new(CORRIDOR, transport);
+
+    let alice = h.join("alice");
+    let bob = h.join("bob");
+    h.send(bob, &ToServerMsg::Login("bob".to_string(), None));
+    h.tick_until(10, |gs| !gs.players.contains_key(&bob));
+    h.tick();
+
+    assert_eq!(h.disconnect_reason(bob),
+               Some("Protocol error: already logged in"));
+    assert!(h.events(alice)
+                .iter()
+                .any(|e| match *e {
+                         Event::PlayerLeft(id, LeaveReason::Kicked) => id == bob,
+                         _ => false,
+                     }));
+}
+
+#[test]
+fn logging_in_twice_gets_kicked_tcp() {
+    logging_in_twice_gets_kicked(Transport::Tcp);
+}
+
+#[test]
+fn logging_in_twice_gets_kicked_udp() {
+    logging_in_twice_gets_kicked(Transport::Udp);
+}
+
+#[test]
+fn shooter_leaves_before_the_kill() {
+    let mut config = ServerConfig::new(CORRIDOR.parse().unwrap());
+    config.rules.player_health = 30.0;
+    config.rules.max_damage = 30.0;
+    let mut h = Harness::with_config(config, Transport::Tcp);
+
+    let alice = h.join("alice");
+    let bob = h.join("bob");
+
+    // the bullet takes a good few ticks to get down the corridor, alice is
+    // gone by the time it gets there
+    h.aim_at(alice, bob);
+    h.press(alice, Button::LeftMouse);
+    h.leave(alice);
+    assert_eq!(h.game_state().bullets.len(), 1);
+
+    let ticks_per_second = h.rules().ticks_per_second;
+    h.tick_until(ticks_per_second, |gs| gs.bullets.is_empty());
+    h.tick();
+
+    assert_eq!(h.player(bob).health, 0.0);
+    assert!(h.events(bob)
+                .iter()
+                .any(|e| match *e {
+                         Event::PlayerDied(id, killer) => id == bob && killer == alice,
+                         _ => false,
+                     }));
+}
+
+#[test]
+fn bad_directions() {
+    let mut h = Harness::new(ROOM, Transport::Tcp);
+    let alice = h.join("alice");
+
+    // too long to square is still a direction
+    h.input(alice, Input::DirChanged(Vector::new(1e30, 0.0)));
+    assert_eq!(h.player(alice).dir, Vector::new(1.0, 0.0));
+    h.input(alice, Input::DirChanged(Vector::new(-f32::MAX, f32::MAX)));
+    let dir = h.player(alice).dir;
+    assert!((dir.magnitude() - 1.0).abs() < 0.001, "dir is {:?}", dir);
+    assert!(dir.x < 0.0 && dir.y > 0.0, "dir is {:?}", dir);
+
+    // the rest are ignored
+    let bad = [Vector::new(1e-30, 0.0),
+               Vector::new(f32::NAN, 1.0),
+               Vector::new(0.0, f32::INFINITY),
+               Vector::new(f32::NEG_INFINITY, f32::NAN)];
+    for &d in &bad {
+        h.input(alice, Input::DirChanged(Vector::new(0.0, 1.0)));
+        h.input(alice, Input::DirChanged(d));
+        assert_eq!(h.player(alice).dir, Vector::new(0.0, 1.0), "after {:?}", d);
+    }
+
+    // and a shot in a huge direction still goes somewhere
+    let bullet_speed = h.rules().bullet_speed;
+    h.input(alice, Input::Press(Button::LeftMouse, Vector::new(0.0, -1e30), 0));
+    let bullet = h.game_state().bullets[0];
+    assert_eq!(bullet.vel, Vector::new(0.0, -bullet_speed));
+}
+
+#[test]
+fn rules() {
+    let mut config = ServerConfig::new(CORRIDOR.parse().unwrap());
+    config.rules.ticks_per_second = 60;
+    config.rules.player_health = 30.0;
+    config.rules.max_damage = 30.0;
+    config.rules.max_players = 2;
+    let mut h = Harness::with_config(config, Transport::Tcp);
+
+    let alice = h.join("alice");
+    let bob = h.join("bob");
+    assert_eq!(h.try_join("carol").unwrap_err(), "Server full");
+    assert_eq!(h.player(bob).health, 30.0);
+
+    // one good hit does it
+    h.aim_at(alice, bob);
+    h.shoot(alice);
+    assert_eq!(h.player(bob).health, 0.0);
+    assert_eq!(h.player(alice).score, 1);
+
+    // someone leaving makes room
+    h.leave(bob);
+    h.join("carol");
+}
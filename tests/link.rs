@@ -0,0 +1,124 @@
+// Reliable and unreliable messages over a lossy, reordering link.
+
+extern crate recurse_arena as ra;
+
+use std::thread;
+use std::time::Duration;
+
+use ra::*;
+
+fn link() -> Link {
+    Link::new(64 * 1024, 10.0)
+}
+
+// everything `to` makes of `datagrams`
+fn deliver(to: &mut Link, datagrams: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut messages = vec![];
+    for d in datagrams {
+        messages.extend(to.receive(d).unwrap());
+    }
+    messages
+}
+
+#[test]
+fn acks_stop_resends() {
+    let (mut a, mut b) = (link(), link());
+    a.queue_reliable(b"hello".to_vec());
+
+    let sent = a.packets(None);
+    assert_eq!(deliver(&mut b, &sent), vec![b"hello".to_vec()]);
+    assert!(a.has_unacked());
+
+    let acks = b.packets(None);
+    deliver(&mut a, &acks);
+    assert!(!a.has_unacked());
+}
+
+#[test]
+fn resends_when_lost() {
+    let (mut a, mut b) = (link(), link());
+    a.queue_reliable(b"hello".to_vec());
+
+    // lost on the way
+    let first = a.packets(None);
+    // not due again yet
+    let again = a.packets(None);
+    assert!(again[0].len() < first[0].len());
+    assert!(deliver(&mut b, &again).is_empty());
+
+    thread::sleep(Duration::from_millis(150));
+    let resent = a.packets(None);
+    assert_eq!(deliver(&mut b, &resent), vec![b"hello".to_vec()]);
+}
+
+#[test]
+fn delivers_in_order() {
+    let (mut a, mut b) = (link(), link());
+    a.queue_reliable(b"one".to_vec());
+    let first = a.packets(None);
+    a.queue_reliable(b"two".to_vec());
+    let second = a.packets(None);
+
+    assert!(deliver(&mut b, &second).is_empty());
+    assert_eq!(deliver(&mut b, &first), vec![b"one".to_vec(), b"two".to_vec()]);
+
+    // and only once
+    assert!(deliver(&mut b, &first).is_empty());
+    assert!(deliver(&mut b, &second).is_empty());
+}
+
+#[test]
+fn drops_stale_unreliable() {
+    let (mut a, mut b) = (link(), link());
+    let old = a.packets(Some(b"old".to_vec()));
+    let new = a.packets(Some(b"new".to_vec()));
+
+    assert_eq!(deliver(&mut b, &new), vec![b"new".to_vec()]);
+    assert!(deliver(&mut b, &old).is_empty());
+    assert!(deliver(&mut b, &new).is_empty());
+}
+
+#[test]
+fn splits_big_messages() {
+    let (mut a, mut b) = (link(), link());
+    let reliable: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    let unreliable: Vec<u8> = (0..3000).map(|i| (i * 7) as u8).collect();
+
+    a.queue_reliable(reliable.clone());
+    let mut sent = a.packets(Some(unreliable.clone()));
+    assert!(sent.len() > 1);
+    assert!(sent.iter().all(|d| d.len() <= 1200));
+
+    // pieces arriving out of order still make the whole message
+    sent.reverse();
+    let messages = deliver(&mut b, &sent);
+    assert_eq!(messages.len(), 2);
+    assert!(messages.contains(&reliable));
+    assert!(messages.contains(&unreliable));
+}
+
+#[test]
+fn refuses_long_messages() {
+    let mut a = link();
+    let mut b = Link::new(100, 10.0);
+
+    a.queue_reliable(vec![0; 200]);
+    let sent = a.packets(None);
+    assert!(b.receive(&sent[0]).is_err());
+
+    let mut a = link();
+    let sent = a.packets(Some(vec![0; 2000]));
+    assert!(sent.iter().any(|d| b.receive(d).is_err()));
+}
+
+#[test]
+fn sends_back_cookie() {
+    let mut client = link();
+    assert_eq!(Link::cookie_of(&client.packets(None)[0]), Some(0));
+
+    client.receive(&Link::challenge(42)).unwrap();
+    assert_eq!(client.cookie(), 42);
+    assert_eq!(Link::cookie_of(&client.packets(None)[0]), Some(42));
+
+    assert_eq!(Link::cookie_of(b"not a packet"), None);
+}
@@ -0,0 +1,70 @@
+// Log filters, and which module gets logged at what.
+
+extern crate log;
+extern crate recurse_arena as ra;
+
+use log::Level;
+
+use ra::*;
+
+#[test]
+fn default_level() {
+    let filter = LogFilter::default();
+    assert!(filter.enabled("recurse_arena::host", Level::Info));
+    assert!(!filter.enabled("recurse_arena::host", Level::Debug));
+
+    let filter: LogFilter = "warn".parse().unwrap();
+    assert!(filter.enabled("recurse_arena", Level::Warn));
+    assert!(!filter.enabled("recurse_arena", Level::Info));
+
+    // an empty filter leaves the default alone
+    let filter: LogFilter = "".parse().unwrap();
+    assert!(filter.enabled("recurse_arena", Level::Info));
+}
+
+#[test]
+fn module_levels() {
+    let filter: LogFilter = " info, recurse_arena::listen = debug ,ears=off".parse().unwrap();
+    assert!(filter.enabled("recurse_arena::listen", Level::Debug));
+    assert!(filter.enabled("recurse_arena::listen::udp", Level::Debug));
+    assert!(!filter.enabled("recurse_arena::listen", Level::Trace));
+    assert!(!filter.enabled("recurse_arena::host", Level::Debug));
+
+    // off silences even errors
+    assert!(!filter.enabled("ears", Level::Error));
+    assert!(!filter.enabled("ears::sound", Level::Error));
+}
+
+#[test]
+fn longest_prefix_wins() {
+    let filter: LogFilter = "recurse_arena::net=trace,recurse_arena=warn".parse().unwrap();
+    assert!(filter.enabled("recurse_arena::net", Level::Trace));
+    assert!(!filter.enabled("recurse_arena::host", Level::Info));
+    assert!(filter.enabled("recurse_arena::host", Level::Warn));
+    // nothing given for it, so the default
+    assert!(filter.enabled("bincode", Level::Info));
+}
+
+#[test]
+fn whole_names_only() {
+    let filter: LogFilter = "recurse_arena::listen=off".parse().unwrap();
+    assert!(!filter.enabled("recurse_arena::listen", Level::Error));
+    assert!(filter.enabled("recurse_arena::listener", Level::Info));
+    assert!(filter.enabled("recurse_arena::list", Level::Info));
+}
+
+#[test]
+fn bad_levels() {
+    let err = "loud".parse::<LogFilter>().unwrap_err();
+    assert!(err.contains("unknown log level"), "{}", err);
+
+    let err = "info,recurse_arena=verbose".parse::<LogFilter>().unwrap_err();
+    assert!(err.contains("unknown log level"), "{}", err);
+}
+
+#[test]
+fn formats() {
+    assert_eq!("text".parse::<LogFormat>(), Ok(LogFormat::Text));
+    assert_eq!("json".parse::<LogFormat>(), Ok(LogFormat::Json));
+    assert!("xml".parse::<LogFormat>().is_err());
+}